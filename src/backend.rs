@@ -0,0 +1,376 @@
+// Where the interpreter's commits, branches and tags end up.
+//
+// Blobs, trees and commits are always written to the interpreter's repository, because incremental
+// trees and merges read them back. The backend decides what else happens with them: `GitBackend`
+// creates the refs in the same repository, and `FastImportBackend` writes a `git fast-import` stream.
+
+use git2::{Repository, Signature, Oid, Commit, ErrorCode};
+use git2::build::CheckoutBuilder;
+use std::collections::HashMap;
+use std::io::Write;
+
+use super::date::format_date;
+use super::error::Error;
+use super::interpreter::{FlatTree, flatten_tree, MODE_GITLINK};
+
+pub trait Backend {
+    // Called once the commit for an id was written to the repository
+    fn commit(&mut self, repo: &Repository, id: &str, commit_oid: Oid) -> Result<(), Error>;
+
+    fn branch(&mut self, repo: &Repository, name: &str, commit_oid: Oid) -> Result<(), Error>;
+
+    fn lightweight_tag(&mut self, repo: &Repository, name: &str, commit_oid: Oid) -> Result<(), Error>;
+
+    fn annotated_tag(&mut self, repo: &Repository, name: &str, commit_oid: Oid, tagger: &Signature, message: &str) -> Result<(), Error>;
+
+    fn symbolic_head(&mut self, repo: &Repository, branch: &str) -> Result<(), Error>;
+
+    fn detached_head(&mut self, repo: &Repository, commit_oid: Oid) -> Result<(), Error>;
+
+    // Called before the work tree is changed
+    fn checkout(&mut self, _repo: &Repository) -> Result<(), Error> {
+        Ok(())
+    }
+
+    // Called after the last command
+    fn finish(&mut self, _repo: &Repository) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+// Writes everything to the repository
+#[derive(Default)]
+pub struct GitBackend {
+    checked_out: bool,
+}
+
+impl GitBackend {
+    // Once HEAD is checked out, the work tree and the index may have changes on top of it. HEAD can't move
+    // anymore, because they wouldn't match it.
+    fn check_head_can_move(&self) -> Result<(), Error> {
+        if self.checked_out {
+            return Err(Error::InvalidValue("HEAD can't move after it was checked out by a worktree, state or in-progress merge command".to_string()));
+        }
+        Ok(())
+    }
+}
+
+impl Backend for GitBackend {
+    fn commit(&mut self, _repo: &Repository, _id: &str, _commit_oid: Oid) -> Result<(), Error> {
+        // Already in the repository
+        Ok(())
+    }
+
+    fn branch(&mut self, repo: &Repository, name: &str, commit_oid: Oid) -> Result<(), Error> {
+        // Moving HEAD's branch moves HEAD
+        let ref_name = format!("refs/heads/{}", name);
+        let is_head = repo.find_reference("HEAD")?.symbolic_target() == Some(ref_name.as_str());
+        if is_head && repo.refname_to_id(&ref_name).ok() != Some(commit_oid) {
+            self.check_head_can_move()?;
+        }
+
+        let commit = repo.find_commit(commit_oid)?;
+        repo.branch(name, &commit, true /* force, even if branch exists */)?;
+        Ok(())
+    }
+
+    fn lightweight_tag(&mut self, repo: &Repository, name: &str, commit_oid: Oid) -> Result<(), Error> {
+        let commit = repo.find_object(commit_oid, None)?;
+        repo.tag_lightweight(name, &commit, true /* force, even if tag exists */)?;
+        Ok(())
+    }
+
+    fn annotated_tag(&mut self, repo: &Repository, name: &str, commit_oid: Oid, tagger: &Signature, message: &str) -> Result<(), Error> {
+        let commit = repo.find_object(commit_oid, None)?;
+        repo.tag(name, &commit, tagger, message, true /* force, even if tag exists */)?;
+        Ok(())
+    }
+
+    fn symbolic_head(&mut self, repo: &Repository, branch: &str) -> Result<(), Error> {
+        self.check_head_can_move()?;
+        repo.set_head(&format!("refs/heads/{}", branch))?;
+        Ok(())
+    }
+
+    fn detached_head(&mut self, repo: &Repository, commit_oid: Oid) -> Result<(), Error> {
+        self.check_head_can_move()?;
+        repo.set_head_detached(commit_oid)?;
+        Ok(())
+    }
+
+    // Checks out HEAD once, so that the index and the work tree match its commit.
+    // Bare repositories, and a HEAD branch that doesn't exist, are left alone.
+    fn checkout(&mut self, repo: &Repository) -> Result<(), Error> {
+        if repo.is_bare() || self.checked_out {
+            return Ok(());
+        }
+        self.checked_out = true;
+
+        match repo.head() {
+            Ok(_) => (),
+            Err(ref e) if e.code() == ErrorCode::UnbornBranch || e.code() == ErrorCode::NotFound => return Ok(()),
+            Err(e) => return Err(e.into())
+        }
+
+        repo.checkout_head(Some(CheckoutBuilder::new().force()))?;
+        Ok(())
+    }
+
+    fn finish(&mut self, repo: &Repository) -> Result<(), Error> {
+        self.checkout(repo)
+    }
+}
+
+// fast-import needs a ref to write commits to. It's deleted at the end of the stream.
+const SCRATCH_REF: &str = "refs/generate-git-repo/scratch";
+
+// Writes a `git fast-import` stream. The repository is only used as scratch space, to compute the trees.
+//
+// Every commit gets a mark, preceded by a comment with its id:
+//
+//     # a
+//     commit refs/generate-git-repo/scratch
+//     mark :1
+//
+// Importing the stream creates the same commit hashes as writing to a repository directly.
+pub struct FastImportBackend<W: Write> {
+    out: W,
+    marks: HashMap<Oid, usize>,
+}
+
+// fast-import paths are unquoted, unless they start with a quote or contain a newline
+fn quote_path(path: &str) -> String {
+    if !path.starts_with('"') && !path.contains('\n') {
+        return path.to_string();
+    }
+
+    let escaped = path.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+    format!("\"{}\"", escaped)
+}
+
+impl<W: Write> FastImportBackend<W> {
+    pub fn new(out: W) -> FastImportBackend<W> {
+        FastImportBackend { out, marks: HashMap::new() }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.out.write_all(bytes).map_err(|e| Error::Io("fast-import stream".to_string(), e))
+    }
+
+    // Raw bytes are prefixed with their length
+    fn write_data(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.write(format!("data {}\n", data.len()).as_bytes())?;
+        self.write(data)?;
+        self.write(b"\n")
+    }
+
+    fn write_signature(&mut self, kind: &str, signature: &Signature) -> Result<(), Error> {
+        let mut line = format!("{} ", kind).into_bytes();
+        line.extend_from_slice(signature.name_bytes());
+        line.extend_from_slice(b" <");
+        line.extend_from_slice(signature.email_bytes());
+        line.extend_from_slice(format!("> {}\n", format_date(signature.when())).as_bytes());
+        self.write(&line)
+    }
+
+    fn mark(&self, commit_oid: Oid) -> Result<usize, Error> {
+        match self.marks.get(&commit_oid) {
+            Some(mark) => Ok(*mark),
+            None => Err(Error::InvalidValue(format!("Commit {} isn't in the fast-import stream", commit_oid)))
+        }
+    }
+
+    // The file changes from the first parent's tree (or from an empty tree)
+    fn write_file_changes(&mut self, repo: &Repository, commit: &Commit) -> Result<(), Error> {
+        let mut before = FlatTree::new();
+        if commit.parent_count() > 0 {
+            flatten_tree(repo, &commit.parent(0)?.tree()?, "", &mut before)?;
+        }
+        let mut after = FlatTree::new();
+        flatten_tree(repo, &commit.tree()?, "", &mut after)?;
+
+        for path in before.keys() {
+            if !after.contains_key(path) {
+                self.write(format!("D {}\n", quote_path(path)).as_bytes())?;
+            }
+        }
+
+        for (path, (mode, oid)) in &after {
+            if before.get(path) == Some(&(*mode, *oid)) {
+                continue;
+            }
+
+            if *mode == MODE_GITLINK {
+                self.write(format!("M {:o} {} {}\n", mode, oid, quote_path(path)).as_bytes())?;
+            } else {
+                self.write(format!("M {:o} inline {}\n", mode, quote_path(path)).as_bytes())?;
+                let blob = repo.find_blob(*oid)?;
+                self.write_data(blob.content())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn reset(&mut self, name: &str, commit_oid: Oid) -> Result<(), Error> {
+        let mark = self.mark(commit_oid)?;
+        self.write(format!("reset {}\nfrom :{}\n\n", name, mark).as_bytes())
+    }
+}
+
+impl<W: Write> Backend for FastImportBackend<W> {
+    fn commit(&mut self, repo: &Repository, id: &str, commit_oid: Oid) -> Result<(), Error> {
+        // Identical commits get the same hash, so they're only written once
+        if self.marks.contains_key(&commit_oid) {
+            return Ok(());
+        }
+
+        let commit = repo.find_commit(commit_oid)?;
+        let parent_marks: Vec<usize> = commit.parent_ids().map(|oid| self.mark(oid)).collect::<Result<_, _>>()?;
+
+        let mark = self.marks.len() + 1;
+        self.marks.insert(commit_oid, mark);
+
+        // Without a parent, the scratch ref has to start over
+        if parent_marks.is_empty() {
+            self.write(format!("reset {}\n\n", SCRATCH_REF).as_bytes())?;
+        }
+
+        self.write(format!("# {}\ncommit {}\nmark :{}\n", id.replace('\n', " "), SCRATCH_REF, mark).as_bytes())?;
+        self.write_signature("author", &commit.author())?;
+        self.write_signature("committer", &commit.committer())?;
+        self.write_data(commit.message_bytes())?;
+
+        for (index, parent_mark) in parent_marks.iter().enumerate() {
+            let kind = if index == 0 { "from" } else { "merge" };
+            self.write(format!("{} :{}\n", kind, parent_mark).as_bytes())?;
+        }
+
+        // Inline data already ends with the optional blank line
+        self.write_file_changes(repo, &commit)
+    }
+
+    fn branch(&mut self, _repo: &Repository, name: &str, commit_oid: Oid) -> Result<(), Error> {
+        self.reset(&format!("refs/heads/{}", name), commit_oid)
+    }
+
+    fn lightweight_tag(&mut self, _repo: &Repository, name: &str, commit_oid: Oid) -> Result<(), Error> {
+        self.reset(&format!("refs/tags/{}", name), commit_oid)
+    }
+
+    fn annotated_tag(&mut self, _repo: &Repository, name: &str, commit_oid: Oid, tagger: &Signature, message: &str) -> Result<(), Error> {
+        let mark = self.mark(commit_oid)?;
+        self.write(format!("tag {}\nfrom :{}\n", name, mark).as_bytes())?;
+        self.write_signature("tagger", tagger)?;
+        self.write_data(message.as_bytes())
+    }
+
+    fn symbolic_head(&mut self, _repo: &Repository, _branch: &str) -> Result<(), Error> {
+        Err(Error::InvalidValue("HEAD can't be set in a fast-import stream".to_string()))
+    }
+
+    fn detached_head(&mut self, _repo: &Repository, _commit_oid: Oid) -> Result<(), Error> {
+        Err(Error::InvalidValue("HEAD can't be set in a fast-import stream".to_string()))
+    }
+
+    fn checkout(&mut self, _repo: &Repository) -> Result<(), Error> {
+        Err(Error::InvalidValue("The work tree can't be changed in a fast-import stream".to_string()))
+    }
+
+    fn finish(&mut self, _repo: &Repository) -> Result<(), Error> {
+        // A null "from" deletes the scratch ref
+        self.write(format!("reset {}\nfrom {}\n\ndone\n", SCRATCH_REF, Oid::zero()).as_bytes())?;
+        self.out.flush().map_err(|e| Error::Io("fast-import stream".to_string(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::command::Command;
+    use super::super::dsl::parse_dsl;
+    use super::super::interpreter::Interpreter;
+    use super::super::init_repository;
+    use std::process::Stdio;
+
+    const FIXTURE: &str = r#"
+config date "1500000000 +0200"
+a: "Initial"
+  README: "Hello"
+  "quoted\"name": "Q"
+b < a: "Commit B" [branch feature]
+  README: "Hello B"
+  docs/guide.md: "Guide"
+c < a: "Commit C"
+  README: "Hello"
+d = merge b c: "Merge" [tag 1.0.0]
+  docs/guide.md: "Guide"
+  README: "Merged"
+branch master -> d
+tag v1 -> a
+"#;
+
+    fn fast_import_stream(commands: &[Command]) -> Vec<u8> {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init_repository(dir.path(), true).unwrap();
+
+        let mut interpreter = Interpreter::with_backend(&repo, FastImportBackend::new(Vec::new())).unwrap();
+        interpreter.interpret_commands(commands).unwrap();
+        interpreter.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn quote_path_test() {
+        assert_eq!(quote_path("dir/file name"), "dir/file name");
+        assert_eq!(quote_path("\"quoted"), "\"\\\"quoted\"");
+        assert_eq!(quote_path("new\nline"), "\"new\\nline\"");
+    }
+
+    #[test]
+    fn fast_import_stream_test() {
+        let stream = String::from_utf8(fast_import_stream(&parse_dsl(FIXTURE).unwrap())).unwrap();
+
+        assert!(stream.starts_with("reset refs/generate-git-repo/scratch\n\n# a\ncommit refs/generate-git-repo/scratch\nmark :1\n"));
+        assert!(stream.contains("# d\ncommit refs/generate-git-repo/scratch\nmark :4\n"));
+        assert!(stream.contains("from :2\nmerge :3\n"));
+        assert!(stream.contains("reset refs/heads/master\nfrom :4\n"));
+        assert!(stream.contains("tag v1\nfrom :1\n"));
+        assert!(stream.ends_with("done\n"));
+    }
+
+    // Importing the stream with git gives the same commits as generating the repository directly
+    #[test]
+    fn fast_import_hashes_test() {
+        let git_available = std::process::Command::new("git").arg("--version").output().is_ok();
+        if !git_available {
+            return;
+        }
+
+        let commands = parse_dsl(FIXTURE).unwrap();
+        let stream = fast_import_stream(&commands);
+
+        let dir = tempfile::tempdir().unwrap();
+        let expected = init_repository(dir.path().join("expected"), true).unwrap();
+        Interpreter::new(&expected).unwrap().interpret_commands(&commands).unwrap();
+
+        let imported = init_repository(dir.path().join("imported"), true).unwrap();
+        let mut child = std::process::Command::new("git")
+            .args(["fast-import", "--quiet"])
+            .current_dir(dir.path().join("imported"))
+            .stdin(Stdio::piped())
+            .spawn().unwrap();
+        child.stdin.take().unwrap().write_all(&stream).unwrap();
+        assert!(child.wait().unwrap().success());
+
+        for name in &["refs/heads/master", "refs/heads/feature", "refs/tags/v1"] {
+            let expected_oid = expected.revparse_single(name).unwrap().peel(git2::ObjectType::Commit).unwrap().id();
+            let imported_oid = imported.revparse_single(name).unwrap().peel(git2::ObjectType::Commit).unwrap().id();
+            assert_eq!(imported_oid, expected_oid, "{}", name);
+        }
+        assert!(imported.find_reference(SCRATCH_REF).is_err());
+    }
+}
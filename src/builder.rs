@@ -0,0 +1,524 @@
+use git2::{Repository, Oid};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use super::command::{Command, TreeNode, Identity, MergeStrategy};
+use super::date::parse_date;
+use super::error::Error;
+use super::interpreter::Interpreter;
+use super::check::check_commands;
+use super::init_repository;
+
+// Builds a list of commands, and runs them to generate a repository.
+//
+//     let generated = RepoBuilder::new(path)
+//         .commit("a").message("Initial commit").file("README", "Hello")
+//         .commit("b").parents(["a"]).file("README", "Hello, world").branch("master")
+//         .build()?;
+//
+//     let oid = generated.oid("b");
+pub struct RepoBuilder {
+    path: PathBuf,
+    bare: bool,
+    date: Option<String>,
+    date_increment: Option<i64>,
+    commands: Vec<Command>,
+}
+
+// The generated repository, and the Git commit hash of every commit id.
+pub struct GeneratedRepo {
+    repo: Repository,
+    id_to_oid_lookup: HashMap<String, Oid>,
+}
+
+impl GeneratedRepo {
+    pub fn repository(&self) -> &Repository {
+        &self.repo
+    }
+
+    pub fn oid(&self, id: &str) -> Option<Oid> {
+        self.id_to_oid_lookup.get(id).cloned()
+    }
+
+    pub fn id_to_oid_lookup(&self) -> &HashMap<String, Oid> {
+        &self.id_to_oid_lookup
+    }
+
+    pub fn into_repository(self) -> Repository {
+        self.repo
+    }
+}
+
+fn to_strings<I, S>(values: I) -> Vec<String>
+  where I: IntoIterator<Item = S>,
+        S: Into<String> {
+    values.into_iter().map(|s| s.into()).collect()
+}
+
+fn identity(name: &str, email: &str) -> Identity {
+    Identity { name: Some(name.to_string()), email: Some(email.to_string()), date: None }
+}
+
+impl RepoBuilder {
+    pub fn new<P: Into<PathBuf>>(path: P) -> RepoBuilder {
+        RepoBuilder {
+            path: path.into(),
+            bare: false,
+            date: None,
+            date_increment: None,
+            commands: Vec::new(),
+        }
+    }
+
+    pub fn bare(mut self, bare: bool) -> RepoBuilder {
+        self.bare = bare;
+        self
+    }
+
+    // Uses deterministic dates, starting at this date (e.g. "1500000000 +0200")
+    pub fn date(mut self, date: &str) -> RepoBuilder {
+        self.date = Some(date.to_string());
+        self
+    }
+
+    pub fn date_increment(mut self, seconds: i64) -> RepoBuilder {
+        self.date_increment = Some(seconds);
+        self
+    }
+
+    // Adds any command, for anything that the builder methods don't cover
+    pub fn command(mut self, command: Command) -> RepoBuilder {
+        self.commands.push(command);
+        self
+    }
+
+    pub fn commit(self, id: &str) -> CommitBuilder {
+        CommitBuilder {
+            repo: self,
+            id: id.to_string(),
+            message: None,
+            parents: Vec::new(),
+            tree: None,
+            delete: Vec::new(),
+            incremental: None,
+            author: None,
+            committer: None,
+            author_date: None,
+            committer_date: None,
+            branches: Vec::new(),
+            tags: Vec::new(),
+        }
+    }
+
+    pub fn merge<I, S>(self, id: &str, commits: I) -> MergeBuilder
+      where I: IntoIterator<Item = S>,
+            S: Into<String> {
+        MergeBuilder {
+            repo: self,
+            id: id.to_string(),
+            commits: to_strings(commits),
+            message: None,
+            tree: None,
+            author: None,
+            committer: None,
+            branches: Vec::new(),
+            tags: Vec::new(),
+            strategy: None,
+            resolutions: None,
+            no_ff: false,
+            in_progress: false,
+        }
+    }
+
+    pub fn branch(self, name: &str, on: &str) -> RepoBuilder {
+        self.command(Command::Branch { name: name.to_string(), on: on.to_string() })
+    }
+
+    // Creates an annotated tag
+    pub fn tag(self, name: &str, on: &str) -> RepoBuilder {
+        self.command(Command::Tag { name: name.to_string(), on: on.to_string(), tagger: None, message: None, lightweight: false })
+    }
+
+    // Points HEAD at a branch. Non-bare repositories check it out.
+    pub fn head(self, branch: &str) -> RepoBuilder {
+        self.command(Command::Head { branch: Some(branch.to_string()), on: None })
+    }
+
+    pub fn detach_head(self, on: &str) -> RepoBuilder {
+        self.command(Command::Head { branch: None, on: Some(on.to_string()) })
+    }
+
+    pub fn commands(&self) -> &[Command] {
+        &self.commands
+    }
+
+    // The commands are checked before the repository is created. If there are problems, the first one is returned.
+    pub fn build(self) -> Result<GeneratedRepo, Error> {
+        if let Some(error) = check_commands(&self.commands).into_iter().next() {
+            return Err(error);
+        }
+
+        let repo = init_repository(&self.path, self.bare)?;
+
+        let id_to_oid_lookup = {
+            let mut interpreter = Interpreter::new(&repo)?;
+
+            if let Some(date_increment) = self.date_increment {
+                interpreter.set_date_increment(date_increment);
+            }
+            if let Some(ref date) = self.date {
+                interpreter.set_date(parse_date(date)?);
+            }
+
+            interpreter.interpret_commands(&self.commands)?;
+            let id_to_oid_lookup = interpreter.id_to_oid_lookup().clone();
+            interpreter.finish()?;
+            id_to_oid_lookup
+        };
+
+        Ok(GeneratedRepo { repo, id_to_oid_lookup })
+    }
+}
+
+// Builds a "commit" command. The command is added once another command is started, or on build().
+pub struct CommitBuilder {
+    repo: RepoBuilder,
+
+    id: String,
+    message: Option<String>,
+    parents: Vec<String>,
+    tree: Option<HashMap<String, TreeNode>>,
+    delete: Vec<String>,
+    incremental: Option<bool>,
+    author: Option<Identity>,
+    committer: Option<Identity>,
+    author_date: Option<String>,
+    committer_date: Option<String>,
+    branches: Vec<String>,
+    tags: Vec<String>,
+}
+
+impl CommitBuilder {
+    pub fn message(mut self, message: &str) -> CommitBuilder {
+        self.message = Some(message.to_string());
+        self
+    }
+
+    pub fn parents<I, S>(mut self, parents: I) -> CommitBuilder
+      where I: IntoIterator<Item = S>,
+            S: Into<String> {
+        self.parents.extend(to_strings(parents));
+        self
+    }
+
+    pub fn file(self, path: &str, contents: &str) -> CommitBuilder {
+        self.tree_node(path, TreeNode::Utf8File(contents.to_string()))
+    }
+
+    pub fn binary_file(self, path: &str, contents: &[u8]) -> CommitBuilder {
+        self.tree_node(path, TreeNode::Hex { hex: hex::encode(contents), mode: None, executable: false })
+    }
+
+    pub fn executable_file(self, path: &str, contents: &str) -> CommitBuilder {
+        self.tree_node(path, TreeNode::Utf8 { utf8: contents.to_string(), mode: None, executable: true })
+    }
+
+    pub fn symlink(self, path: &str, target: &str) -> CommitBuilder {
+        self.tree_node(path, TreeNode::Symlink { symlink: target.to_string() })
+    }
+
+    // Adds any kind of tree entry
+    pub fn tree_node(mut self, path: &str, node: TreeNode) -> CommitBuilder {
+        self.tree.get_or_insert_with(HashMap::new).insert(path.to_string(), node);
+        self
+    }
+
+    // Only used in incremental mode
+    pub fn delete(mut self, path: &str) -> CommitBuilder {
+        self.delete.push(path.to_string());
+        self
+    }
+
+    pub fn incremental(mut self, incremental: bool) -> CommitBuilder {
+        self.incremental = Some(incremental);
+        self
+    }
+
+    pub fn author(mut self, name: &str, email: &str) -> CommitBuilder {
+        self.author = Some(identity(name, email));
+        self
+    }
+
+    pub fn committer(mut self, name: &str, email: &str) -> CommitBuilder {
+        self.committer = Some(identity(name, email));
+        self
+    }
+
+    pub fn author_date(mut self, date: &str) -> CommitBuilder {
+        self.author_date = Some(date.to_string());
+        self
+    }
+
+    pub fn committer_date(mut self, date: &str) -> CommitBuilder {
+        self.committer_date = Some(date.to_string());
+        self
+    }
+
+    pub fn branch(mut self, name: &str) -> CommitBuilder {
+        self.branches.push(name.to_string());
+        self
+    }
+
+    // Creates a lightweight tag
+    pub fn tag(mut self, name: &str) -> CommitBuilder {
+        self.tags.push(name.to_string());
+        self
+    }
+
+    // Adds the command, and returns to the repository builder
+    pub fn finish(self) -> RepoBuilder {
+        let command = Command::Commit {
+            id: self.id,
+            message: self.message,
+            parents: self.parents,
+            tree: self.tree,
+            delete: self.delete,
+            incremental: self.incremental,
+            author: self.author,
+            committer: self.committer,
+            author_date: self.author_date,
+            committer_date: self.committer_date,
+            branches: if self.branches.is_empty() { None } else { Some(self.branches) },
+            tags:     if self.tags.is_empty() { None } else { Some(self.tags) },
+        };
+
+        self.repo.command(command)
+    }
+
+    pub fn commit(self, id: &str) -> CommitBuilder {
+        self.finish().commit(id)
+    }
+
+    pub fn merge<I, S>(self, id: &str, commits: I) -> MergeBuilder
+      where I: IntoIterator<Item = S>,
+            S: Into<String> {
+        self.finish().merge(id, commits)
+    }
+
+    pub fn build(self) -> Result<GeneratedRepo, Error> {
+        self.finish().build()
+    }
+}
+
+// Builds a "merge" command. The command is added once another command is started, or on build().
+pub struct MergeBuilder {
+    repo: RepoBuilder,
+
+    id: String,
+    commits: Vec<String>,
+    message: Option<String>,
+    tree: Option<HashMap<String, TreeNode>>,
+    author: Option<Identity>,
+    committer: Option<Identity>,
+    branches: Vec<String>,
+    tags: Vec<String>,
+    strategy: Option<MergeStrategy>,
+    resolutions: Option<HashMap<String, TreeNode>>,
+    no_ff: bool,
+    in_progress: bool,
+}
+
+impl MergeBuilder {
+    pub fn message(mut self, message: &str) -> MergeBuilder {
+        self.message = Some(message.to_string());
+        self
+    }
+
+    pub fn file(mut self, path: &str, contents: &str) -> MergeBuilder {
+        let node = TreeNode::Utf8File(contents.to_string());
+        self.tree.get_or_insert_with(HashMap::new).insert(path.to_string(), node);
+        self
+    }
+
+    pub fn author(mut self, name: &str, email: &str) -> MergeBuilder {
+        self.author = Some(identity(name, email));
+        self
+    }
+
+    pub fn committer(mut self, name: &str, email: &str) -> MergeBuilder {
+        self.committer = Some(identity(name, email));
+        self
+    }
+
+    pub fn branch(mut self, name: &str) -> MergeBuilder {
+        self.branches.push(name.to_string());
+        self
+    }
+
+    // Creates a lightweight tag
+    pub fn tag(mut self, name: &str) -> MergeBuilder {
+        self.tags.push(name.to_string());
+        self
+    }
+
+    // Computes the tree by merging the parents' trees
+    pub fn strategy(mut self, strategy: MergeStrategy) -> MergeBuilder {
+        self.strategy = Some(strategy);
+        self
+    }
+
+    pub fn resolution(mut self, path: &str, contents: &str) -> MergeBuilder {
+        let node = TreeNode::Utf8File(contents.to_string());
+        self.resolutions.get_or_insert_with(HashMap::new).insert(path.to_string(), node);
+        self
+    }
+
+    pub fn no_ff(mut self) -> MergeBuilder {
+        self.no_ff = true;
+        self
+    }
+
+    // Leaves the merge in progress, instead of committing it
+    pub fn in_progress(mut self) -> MergeBuilder {
+        self.in_progress = true;
+        self
+    }
+
+    // Adds the command, and returns to the repository builder
+    pub fn finish(self) -> RepoBuilder {
+        let command = Command::Merge {
+            id: self.id,
+            commits: self.commits,
+            message: self.message,
+            tree: self.tree,
+            delete: Vec::new(),
+            incremental: None,
+            author: self.author,
+            committer: self.committer,
+            author_date: None,
+            committer_date: None,
+            branches: if self.branches.is_empty() { None } else { Some(self.branches) },
+            tags:     if self.tags.is_empty() { None } else { Some(self.tags) },
+            strategy: self.strategy,
+            resolutions: self.resolutions,
+            no_ff: self.no_ff,
+            in_progress: self.in_progress,
+        };
+
+        self.repo.command(command)
+    }
+
+    pub fn commit(self, id: &str) -> CommitBuilder {
+        self.finish().commit(id)
+    }
+
+    pub fn merge<I, S>(self, id: &str, commits: I) -> MergeBuilder
+      where I: IntoIterator<Item = S>,
+            S: Into<String> {
+        self.finish().merge(id, commits)
+    }
+
+    pub fn build(self) -> Result<GeneratedRepo, Error> {
+        self.finish().build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder_test() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let generated = RepoBuilder::new(dir.path())
+            .bare(true)
+            .date("1500000000 +0000")
+            .commit("a").message("Initial commit").file("README", "Hello")
+            .commit("b").parents(["a"]).file("README", "Hello, world").executable_file("run.sh", "#!/bin/sh")
+            .commit("c").parents(["a"]).author("Alice", "alice@example.org")
+            .merge("d", ["b", "c"]).no_ff().branch("master")
+            .finish()
+            .tag("1.0.0", "d")
+            .build()
+            .unwrap();
+
+        let repo = generated.repository();
+
+        let b = repo.find_commit(generated.oid("b").unwrap()).unwrap();
+        assert_eq!(b.parent_ids().collect::<Vec<_>>(), vec![generated.oid("a").unwrap()]);
+        assert_eq!(b.tree().unwrap().get_name("run.sh").unwrap().filemode(), 0o100755);
+
+        let c = repo.find_commit(generated.oid("c").unwrap()).unwrap();
+        assert_eq!(c.author().name(), Some("Alice"));
+        assert_eq!(c.message(), Some("c"));
+
+        let d = repo.find_commit(generated.oid("d").unwrap()).unwrap();
+        assert_eq!(d.parent_ids().collect::<Vec<_>>(), vec![b.id(), c.id()]);
+        assert_eq!(repo.refname_to_id("refs/heads/master").unwrap(), d.id());
+        assert!(repo.revparse_single("1.0.0").unwrap().peel_to_commit().unwrap().id() == d.id());
+
+        assert_eq!(generated.oid("nope"), None);
+    }
+
+    #[test]
+    fn checkout_test() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let generated = RepoBuilder::new(dir.path())
+            .commit("a").file("README", "Hello").branch("master")
+            .commit("b").parents(["a"]).file("README", "Hello B").symlink("link", "README").executable_file("run.sh", "#!/bin/sh").branch("feature")
+            .finish()
+            .head("feature")
+            .build()
+            .unwrap();
+
+        let repo = generated.repository();
+        assert_eq!(repo.head().unwrap().name(), Some("refs/heads/feature"));
+        assert!(repo.statuses(None).unwrap().is_empty());
+
+        let workdir = repo.workdir().unwrap();
+        assert_eq!(std::fs::read_to_string(workdir.join("README")).unwrap(), "Hello B");
+        assert!(std::fs::symlink_metadata(workdir.join("link")).unwrap().file_type().is_symlink());
+        assert!(workdir.join("run.sh").exists());
+    }
+
+    #[test]
+    fn detached_head_test() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let generated = RepoBuilder::new(dir.path())
+            .commit("a").file("README", "Hello").branch("master")
+            .commit("b").parents(["a"]).file("README", "Hello B")
+            .finish()
+            .detach_head("b")
+            .build()
+            .unwrap();
+
+        let repo = generated.repository();
+        assert!(repo.head_detached().unwrap());
+        assert_eq!(repo.head().unwrap().target(), generated.oid("b"));
+        assert_eq!(std::fs::read_to_string(repo.workdir().unwrap().join("README")).unwrap(), "Hello B");
+    }
+
+    #[test]
+    fn builder_error_test() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let result = RepoBuilder::new(dir.path())
+            .commit("a").parents(["missing"])
+            .build();
+
+        // Nothing is written
+        assert!(std::fs::read_dir(dir.path()).unwrap().next().is_none());
+
+        match result {
+            Err(Error::Command { index: 0, error }) => match *error {
+                Error::UnknownId(ref id) => assert_eq!(id, "missing"),
+                ref other => panic!("Expected an unknown id: {:?}", other)
+            },
+            Err(other) => panic!("Expected an unknown id: {:?}", other),
+            Ok(_) => panic!("Expected an error")
+        }
+    }
+}
@@ -69,6 +69,7 @@ mod tests {
     fn can_fastforward_to(commits: &[u8], adjacency: &[[u8; 2]], to: u8) {
         assert_eq!(can_fastforward_helper(commits, adjacency), Some(to));
     }
+    #[allow(clippy::bool_assert_comparison)]
     fn can_fastforward_false(commits: &[u8], adjacency: &[[u8; 2]]) {
         assert_eq!(can_fastforward_helper(commits, adjacency).is_some(), false);
     }
@@ -0,0 +1,351 @@
+// Validates a list of commands without a repository, so that every problem can be reported at once,
+// before anything is written.
+//
+// Only problems that don't depend on the repository are found. e.g. merge conflicts are only found
+// by the interpreter.
+
+use git2::{Oid, Reference};
+use std::collections::HashMap;
+
+use super::command::{Command, TreeNode, Identity, OperationState};
+use super::date::parse_date;
+use super::error::Error;
+use super::interpreter::{check_tree, check_no_deletions};
+
+struct Checker {
+    // The ids defined so far. Gitlinks can refer to them, so the trees are checked with placeholder hashes.
+    ids: HashMap<String, Oid>,
+
+    // Follows "config", so that deletions in non-incremental trees are found
+    incremental: bool,
+
+    errors: Vec<Error>,
+}
+
+impl Checker {
+    fn check_defined(&mut self, id: &str) {
+        if !self.ids.contains_key(id) {
+            self.errors.push(Error::UnknownId(id.to_string()));
+        }
+    }
+
+    fn define(&mut self, id: &str) {
+        if self.ids.insert(id.to_string(), Oid::zero()).is_some() {
+            self.errors.push(Error::DuplicateId(id.to_string()));
+        }
+    }
+
+    fn check_tree(&mut self, tree: &Option<HashMap<String, TreeNode>>, delete: &[String]) {
+        let empty_tree = HashMap::new();
+        let tree = tree.as_ref().unwrap_or(&empty_tree);
+
+        if let Err(e) = check_tree(tree, delete, &self.ids) {
+            self.errors.push(e);
+        }
+    }
+
+    fn check_deletions(&mut self, tree: &Option<HashMap<String, TreeNode>>, delete: &[String], incremental: Option<bool>) {
+        if incremental.unwrap_or(self.incremental) {
+            return;
+        }
+        if let Err(e) = check_no_deletions(tree, delete) {
+            self.errors.push(e);
+        }
+    }
+
+    fn check_date(&mut self, date: &Option<String>) {
+        if let Some(Err(e)) = date.as_deref().map(parse_date) {
+            self.errors.push(e);
+        }
+    }
+
+    fn check_identity(&mut self, identity: &Option<Identity>) {
+        if let Some(identity) = identity {
+            self.check_date(&identity.date);
+        }
+    }
+
+    // e.g. ("refs/heads/", "master")
+    fn check_ref_name(&mut self, prefix: &str, name: &str) {
+        let valid = !name.contains('\0') && Reference::is_valid_name(&format!("{}{}", prefix, name));
+        if !valid {
+            self.errors.push(Error::InvalidRefName(name.to_string()));
+        }
+    }
+
+    fn check_refs(&mut self, branches: &Option<Vec<String>>, tags: &Option<Vec<String>>) {
+        for name in branches.iter().flatten() {
+            self.check_ref_name("refs/heads/", name);
+        }
+        for name in tags.iter().flatten() {
+            self.check_ref_name("refs/tags/", name);
+        }
+    }
+
+    fn check_state(&mut self, state: &OperationState) {
+        match state {
+            OperationState::RebaseMerge { onto, orig_head, head, branch, done, todo, .. } |
+            OperationState::RebaseApply { onto, orig_head, head, branch, done, todo } => {
+                for id in std::iter::once(onto).chain(std::iter::once(orig_head)).chain(head).chain(done).chain(todo) {
+                    self.check_defined(id);
+                }
+                if let Some(branch) = branch {
+                    self.check_ref_name("refs/heads/", branch);
+                }
+            },
+            OperationState::CherryPick { commit } | OperationState::Revert { commit } => self.check_defined(commit),
+            OperationState::Bisect { bad, good, skip, head } => {
+                for id in std::iter::once(bad).chain(good).chain(skip).chain(head) {
+                    self.check_defined(id);
+                }
+            },
+        }
+    }
+
+    fn check_command(&mut self, command: &Command) {
+        match command {
+            Command::Commit { id, parents, tree, delete, incremental, author, committer, author_date, committer_date, branches, tags, .. } => {
+                for parent in parents {
+                    self.check_defined(parent);
+                }
+                self.check_tree(tree, delete);
+                self.check_deletions(tree, delete, *incremental);
+                self.check_identity(author);
+                self.check_identity(committer);
+                self.check_date(author_date);
+                self.check_date(committer_date);
+                self.check_refs(branches, tags);
+                self.define(id);
+            },
+
+            Command::Merge { id, commits, tree, delete, incremental, author, committer, author_date, committer_date,
+                             branches, tags, strategy, resolutions, no_ff, in_progress, .. } => {
+                if commits.is_empty() {
+                    self.errors.push(Error::EmptyMerge);
+                }
+                for commit in commits {
+                    self.check_defined(commit);
+                }
+                self.check_tree(tree, delete);
+                if *in_progress {
+                    if tree.is_some() || !delete.is_empty() || incremental.is_some() || strategy.is_some() || resolutions.is_some() ||
+                       author.is_some() || committer.is_some() || author_date.is_some() || committer_date.is_some() || *no_ff ||
+                       branches.is_some() || tags.is_some() {
+                        self.errors.push(Error::InvalidValue("An in-progress merge can't have a tree, delete list, incremental, strategy, resolutions, author, committer, dates, no_ff, branches or tags".to_string()));
+                    }
+                } else if strategy.is_none() {
+                    self.check_deletions(tree, delete, *incremental);
+                }
+                if resolutions.is_some() {
+                    if strategy.is_none() && !*in_progress {
+                        self.errors.push(Error::InvalidValue("Resolutions can only be used with a merge strategy".to_string()));
+                    }
+                    self.check_tree(resolutions, &[]);
+                }
+                self.check_identity(author);
+                self.check_identity(committer);
+                self.check_date(author_date);
+                self.check_date(committer_date);
+                self.check_refs(branches, tags);
+                // An in-progress merge doesn't create a commit
+                if !*in_progress {
+                    self.define(id);
+                }
+            },
+
+            Command::CherryPick { id, commit, parent, resolutions, author, committer, author_date, committer_date, branches, tags, .. } |
+            Command::Revert { id, commit, parent, resolutions, author, committer, author_date, committer_date, branches, tags, .. } => {
+                self.check_defined(commit);
+                self.check_defined(parent);
+                if resolutions.is_some() {
+                    self.check_tree(resolutions, &[]);
+                }
+                self.check_identity(author);
+                self.check_identity(committer);
+                self.check_date(author_date);
+                self.check_date(committer_date);
+                self.check_refs(branches, tags);
+                self.define(id);
+            },
+
+            Command::Branch { name, on } => {
+                self.check_defined(on);
+                self.check_ref_name("refs/heads/", name);
+            },
+
+            Command::Tag { name, on, tagger, .. } => {
+                self.check_defined(on);
+                self.check_identity(tagger);
+                self.check_ref_name("refs/tags/", name);
+            },
+
+            Command::Worktree { staged, unstaged, untracked, ignored } => {
+                for tree in &[staged, unstaged, untracked, ignored] {
+                    if tree.is_some() {
+                        self.check_tree(tree, &[]);
+                    }
+                }
+            },
+
+            Command::State(state) => self.check_state(state),
+
+            Command::Head { branch, on } => {
+                match (branch, on) {
+                    (Some(branch), None) => self.check_ref_name("refs/heads/", branch),
+                    (None, Some(on)) => self.check_defined(on),
+                    _ => self.errors.push(Error::InvalidValue("A head command needs either a branch or a commit (\"on\")".to_string()))
+                }
+            },
+
+            Command::Config { date, tree, incremental, .. } => {
+                self.check_date(date);
+                if tree.is_some() {
+                    self.check_tree(tree, &[]);
+                    self.check_deletions(tree, &[], Some(false));
+                }
+                if let Some(incremental) = incremental {
+                    self.incremental = *incremental;
+                }
+            },
+        }
+    }
+}
+
+// Returns every problem, each with the index of its command
+pub fn check_commands(commands: &[Command]) -> Vec<Error> {
+    let mut checker = Checker { ids: HashMap::new(), incremental: false, errors: Vec::new() };
+    let mut errors = Vec::new();
+
+    for (index, command) in commands.iter().enumerate() {
+        checker.check_command(command);
+        errors.extend(checker.errors.drain(..).map(|e| e.in_command(index)));
+    }
+
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::dsl::parse_dsl;
+
+    fn check_dsl(source: &str) -> Vec<String> {
+        check_commands(&parse_dsl(source).unwrap()).iter().map(|e| e.to_string()).collect()
+    }
+
+    #[test]
+    fn valid_test() {
+        let errors = check_dsl(r#"
+a: "Initial" [branch dev]
+  README: "Hello"
+b < a
+c = merge a b [tag 1.0.0]
+branch master -> c
+"#);
+        assert!(errors.is_empty(), "{:?}", errors);
+    }
+
+    #[test]
+    fn all_problems_test() {
+        let errors = check_dsl(r#"
+a
+  README: "Hello"
+  - README
+b < x
+a < b
+c = merge b, y [branch bad..name]
+tag v1 -> a
+branch bad~name -> z
+"#);
+        assert_eq!(errors, vec![
+            "command at index 0: File or directory already exists: README",
+            "command at index 0: Only incremental trees can delete paths: README",
+            "command at index 1: ID doesn't exist: x",
+            "command at index 2: ID already exists: a",
+            "command at index 3: ID doesn't exist: y",
+            "command at index 3: Invalid branch or tag name: bad..name",
+            "command at index 5: ID doesn't exist: z",
+            "command at index 5: Invalid branch or tag name: bad~name",
+        ]);
+    }
+
+    #[test]
+    fn empty_merge_and_date_test() {
+        let commands: Vec<Command> = serde_json::from_str(r#"[
+            { "type": "merge", "id": "m", "commits": [] },
+            { "type": "commit", "id": "a", "author_date": "yesterday" },
+            { "type": "merge", "id": "b", "commits": ["a"], "resolutions": { "README": "Resolved" } }
+        ]"#).unwrap();
+
+        let errors: Vec<String> = check_commands(&commands).iter().map(|e| e.to_string()).collect();
+        assert_eq!(errors, vec![
+            "command at index 0: Commits cannot be empty",
+            "command at index 1: Invalid date (expected \"<seconds> <+hhmm>\"): yesterday",
+            "command at index 2: Resolutions can only be used with a merge strategy",
+        ]);
+    }
+
+    #[test]
+    fn state_test() {
+        let commands: Vec<Command> = serde_json::from_str(r#"[
+            { "type": "commit", "id": "a" },
+            { "type": "state", "state": "rebase-merge", "onto": "a", "orig_head": "x", "branch": "bad..name", "todo": ["a", "y"] },
+            { "type": "state", "state": "bisect", "bad": "a", "good": ["z"] },
+            { "type": "merge", "id": "m", "commits": ["a"], "in_progress": true, "strategy": "recursive", "tags": ["1.0.0"] },
+            { "type": "merge", "id": "m", "commits": ["a"], "in_progress": true, "author": { "name": "Someone" } },
+            { "type": "merge", "id": "m", "commits": ["a"], "in_progress": true, "committer": { "name": "Someone" } },
+            { "type": "merge", "id": "m", "commits": ["a"], "in_progress": true, "author_date": "1500000000 +0000" },
+            { "type": "merge", "id": "m", "commits": ["a"], "in_progress": true, "committer_date": "1500000000 +0000" },
+            { "type": "merge", "id": "m", "commits": ["a"], "in_progress": true, "incremental": true },
+            { "type": "merge", "id": "m", "commits": ["a"], "in_progress": true, "no_ff": true },
+            { "type": "merge", "id": "m", "commits": ["a"], "in_progress": true }
+        ]"#).unwrap();
+
+        let errors: Vec<String> = check_commands(&commands).iter().map(|e| e.to_string()).collect();
+        assert_eq!(errors, vec![
+            "command at index 1: ID doesn't exist: x",
+            "command at index 1: ID doesn't exist: y",
+            "command at index 1: Invalid branch or tag name: bad..name",
+            "command at index 2: ID doesn't exist: z",
+            "command at index 3: An in-progress merge can't have a tree, delete list, incremental, strategy, resolutions, author, committer, dates, no_ff, branches or tags",
+            "command at index 4: An in-progress merge can't have a tree, delete list, incremental, strategy, resolutions, author, committer, dates, no_ff, branches or tags",
+            "command at index 5: An in-progress merge can't have a tree, delete list, incremental, strategy, resolutions, author, committer, dates, no_ff, branches or tags",
+            "command at index 6: An in-progress merge can't have a tree, delete list, incremental, strategy, resolutions, author, committer, dates, no_ff, branches or tags",
+            "command at index 7: An in-progress merge can't have a tree, delete list, incremental, strategy, resolutions, author, committer, dates, no_ff, branches or tags",
+            "command at index 8: An in-progress merge can't have a tree, delete list, incremental, strategy, resolutions, author, committer, dates, no_ff, branches or tags",
+            "command at index 9: An in-progress merge can't have a tree, delete list, incremental, strategy, resolutions, author, committer, dates, no_ff, branches or tags",
+        ]);
+    }
+
+    #[test]
+    fn path_test() {
+        let commands: Vec<Command> = serde_json::from_str(r#"[
+            { "type": "commit", "id": "a", "tree": { "../a.txt": "A" } },
+            { "type": "worktree", "untracked": { "../escaped.txt": "Escaped" } },
+            { "type": "worktree", "staged": { "/tmp/absolute.txt": "Absolute" } },
+            { "type": "worktree", "unstaged": { ".git/config": "Config" } },
+            { "type": "worktree", "ignored": { "build//output.o": "Binary" } }
+        ]"#).unwrap();
+
+        let errors: Vec<String> = check_commands(&commands).iter().map(|e| e.to_string()).collect();
+        assert_eq!(errors, vec![
+            "command at index 0: Invalid path: ../a.txt",
+            "command at index 1: Invalid path: ../escaped.txt",
+            "command at index 2: Invalid path: /tmp/absolute.txt",
+            "command at index 3: Invalid path: .git/config",
+            "command at index 4: Invalid path: build//output.o",
+        ]);
+    }
+
+    #[test]
+    fn deletions_test() {
+        let errors = check_dsl(r#"
+a
+  - README
+config incremental
+b < a
+  - README
+"#);
+        assert_eq!(errors, vec!["command at index 0: Only incremental trees can delete paths: README"]);
+    }
+}
@@ -1,20 +1,177 @@
-use serde::{Deserialize};
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::{HashMap, BTreeMap};
 
-// Used as serde deserialization defaults
+// Used as serde deserialization defaults, and to skip serializing default values
 fn empty_vec_string() -> Vec<String> { vec![] }
 fn false_boolean() -> bool { false }
+fn is_false(value: &bool) -> bool { !*value }
 
-#[derive(Deserialize, Debug)]
+// Serializes trees with sorted paths, so that the output is stable
+fn sorted_tree<S: Serializer>(tree: &Option<HashMap<String, TreeNode>>, serializer: S) -> Result<S::Ok, S::Error> {
+    let sorted: Option<BTreeMap<&String, &TreeNode>> = tree.as_ref().map(|tree| tree.iter().collect());
+    sorted.serialize(serializer)
+}
+
+#[derive(Deserialize, Serialize, Debug)]
 #[serde(untagged)]
 pub enum TreeNode {
   Utf8File(String),
-  
-  // possible feature: accept an object with arguments, such as file permissions
+
+  // The object forms accept file permissions:
+  // { "mode": "100755" } or { "executable": true }
+
+  // e.g. { "utf8": "#!/bin/sh" }
+  Utf8 {
+    utf8: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mode: Option<FileMode>,
+    #[serde(default = "false_boolean", skip_serializing_if = "is_false")]
+    executable: bool,
+  },
+
+  // Binary contents, e.g. { "base64": "AAEC/w==" }
+  Base64 {
+    base64: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mode: Option<FileMode>,
+    #[serde(default = "false_boolean", skip_serializing_if = "is_false")]
+    executable: bool,
+  },
+
+  // Binary contents, e.g. { "hex": "000102ff" }
+  Hex {
+    hex: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mode: Option<FileMode>,
+    #[serde(default = "false_boolean", skip_serializing_if = "is_false")]
+    executable: bool,
+  },
+
+  // Symbolic link to the target path, e.g. { "symlink": "../target/path" }
+  // The target doesn't need to exist.
+  Symlink { symlink: String },
+
+  // Submodule commit, e.g. { "gitlink": "<commit hash>" } or { "gitlink": { "id": "a" } }
+  // If a url is set, the submodule is also added to .gitmodules
+  Gitlink {
+    gitlink: GitlinkTarget,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+  },
+
+  // null: deletes the path from the parent's tree (only in incremental mode)
+  Deleted,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum GitlinkTarget {
+  // A Git commit hash, e.g. from a repository generated by another run
+  Hash(String),
+
+  // A commit identifier from this run
+  Id { id: String },
+}
+
+// Octal file mode, either as a string ("100755") or a number (100755)
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(untagged)]
+pub enum FileMode {
+  Number(u32),
+  String(String),
+}
+
+
+#[derive(Deserialize, Serialize, Debug)]
+pub enum MergeStrategy {
+    // Three-way merge of the parents' trees, like `git merge`
+    #[serde(rename = "recursive")]
+    Recursive,
 }
 
+// An author, committer or tagger. Unset fields fall back to the defaults from "config".
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Identity {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name:  Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
 
-#[derive(Deserialize, Debug)]
+    // In Git's internal format, e.g. "1500000000 +0200"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date:  Option<String>,
+}
+
+// An operation that Git stops in the middle of, for the user to continue or abort.
+// Ids refer to commits created by earlier commands.
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(tag = "state")]
+pub enum OperationState {
+    // .git/rebase-merge, used by `git rebase` and `git rebase -i`
+    #[serde(rename = "rebase-merge")]
+    RebaseMerge {
+        onto: String,
+        // The branch's commit before the rebase started
+        orig_head: String,
+        // The commit that HEAD is detached at. Defaults to "onto".
+        #[serde(skip_serializing_if = "Option::is_none")]
+        head: Option<String>,
+        // The branch being rebased. Without it, a detached HEAD is being rebased.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        branch: Option<String>,
+
+        // The commits that were already picked, and the ones left in the todo list
+        #[serde(default = "empty_vec_string", skip_serializing_if = "Vec::is_empty")]
+        done: Vec<String>,
+        #[serde(default = "empty_vec_string", skip_serializing_if = "Vec::is_empty")]
+        todo: Vec<String>,
+
+        #[serde(default = "false_boolean", skip_serializing_if = "is_false")]
+        interactive: bool,
+    },
+
+    // .git/rebase-apply, used by `git rebase --apply`
+    #[serde(rename = "rebase-apply")]
+    RebaseApply {
+        onto: String,
+        orig_head: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        head: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        branch: Option<String>,
+
+        #[serde(default = "empty_vec_string", skip_serializing_if = "Vec::is_empty")]
+        done: Vec<String>,
+        #[serde(default = "empty_vec_string", skip_serializing_if = "Vec::is_empty")]
+        todo: Vec<String>,
+    },
+
+    // The commit's changes are applied to HEAD, with conflicts left in the index and the work tree
+    #[serde(rename = "cherry-pick")]
+    CherryPick {
+        commit: String,
+    },
+
+    // The commit's changes are reversed on top of HEAD, with conflicts left in the index and the work tree
+    #[serde(rename = "revert")]
+    Revert {
+        commit: String,
+    },
+
+    #[serde(rename = "bisect")]
+    Bisect {
+        bad: String,
+        #[serde(default = "empty_vec_string", skip_serializing_if = "Vec::is_empty")]
+        good: Vec<String>,
+        #[serde(default = "empty_vec_string", skip_serializing_if = "Vec::is_empty")]
+        skip: Vec<String>,
+        // The commit being tested, that HEAD is detached at. Without it, HEAD doesn't move.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        head: Option<String>,
+    },
+}
+
+#[derive(Deserialize, Serialize, Debug)]
 #[serde(tag = "type")]
 pub enum Command {
     #[serde(rename = "commit")]
@@ -22,15 +179,38 @@ pub enum Command {
     Commit {
         id: String,
 
+        #[serde(skip_serializing_if = "Option::is_none")]
         message: Option<String>,
 
-        #[serde(default = "empty_vec_string")]
+        #[serde(default = "empty_vec_string", skip_serializing_if = "Vec::is_empty")]
         parents: Vec<String>,
 
+        #[serde(skip_serializing_if = "Option::is_none", serialize_with = "sorted_tree")]
         tree: Option<HashMap<String, TreeNode>>,
 
+        // Paths to delete from the parent's tree (only in incremental mode)
+        #[serde(default = "empty_vec_string", skip_serializing_if = "Vec::is_empty")]
+        delete: Vec<String>,
+
+        // Overrides the "incremental" setting from "config" for this commit
+        #[serde(skip_serializing_if = "Option::is_none")]
+        incremental: Option<bool>,
+
+        #[serde(skip_serializing_if = "Option::is_none")]
+        author:    Option<Identity>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        committer: Option<Identity>,
+
+        // Dates in Git's internal format, e.g. "1500000000 +0200"
+        #[serde(skip_serializing_if = "Option::is_none")]
+        author_date:    Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        committer_date: Option<String>,
+
         // If these are set, assign branches/tags to the commit
+        #[serde(skip_serializing_if = "Option::is_none")]
         branches: Option<Vec<String>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
         tags:     Option<Vec<String>>
     },
 
@@ -41,18 +221,110 @@ pub enum Command {
         commits: Vec<String>,
 
         // Only used if a merge commit is made
+        #[serde(skip_serializing_if = "Option::is_none")]
         message: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none", serialize_with = "sorted_tree")]
         tree: Option<HashMap<String, TreeNode>>,
+        #[serde(default = "empty_vec_string", skip_serializing_if = "Vec::is_empty")]
+        delete: Vec<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        incremental:    Option<bool>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        author:         Option<Identity>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        committer:      Option<Identity>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        author_date:    Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        committer_date: Option<String>,
 
         // If these are set, assign branches/tags to the commit
+        #[serde(skip_serializing_if = "Option::is_none")]
         branches: Option<Vec<String>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
         tags:     Option<Vec<String>>,
 
+        // If set, the merge commit's tree is computed from the parents' trees, instead of using "tree".
+        #[serde(skip_serializing_if = "Option::is_none")]
+        strategy: Option<MergeStrategy>,
+        // The contents of conflicting paths (or null to delete them)
+        #[serde(skip_serializing_if = "Option::is_none", serialize_with = "sorted_tree")]
+        resolutions: Option<HashMap<String, TreeNode>>,
+
         // Disable fast-forward merges. Fast-forward is enabled by default.
-        #[serde(default = "false_boolean")]
+        #[serde(default = "false_boolean", skip_serializing_if = "is_false")]
         no_ff: bool,
+
+        // Stops before the merge commit, like a merge with conflicts: the index, the work tree, MERGE_HEAD and
+        // MERGE_MSG are left for the user to resolve. HEAD must be checked out at the first commit.
+        // No commit is created, so the id can't be used by later commands.
+        #[serde(default = "false_boolean", skip_serializing_if = "is_false")]
+        in_progress: bool,
     },
     
+    // Commits the changes of another commit on top of "parent", like `git cherry-pick -x`.
+    // The author defaults to the original commit's author.
+    #[serde(rename = "cherry-pick")]
+    CherryPick {
+        id: String,
+
+        // The commit whose changes are applied, and the new commit's parent
+        commit: String,
+        parent: String,
+
+        // Defaults to Git's message: the original message, and "(cherry picked from commit ...)"
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+        // The contents of conflicting paths (or null to delete them)
+        #[serde(skip_serializing_if = "Option::is_none", serialize_with = "sorted_tree")]
+        resolutions: Option<HashMap<String, TreeNode>>,
+
+        #[serde(skip_serializing_if = "Option::is_none")]
+        author:         Option<Identity>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        committer:      Option<Identity>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        author_date:    Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        committer_date: Option<String>,
+
+        #[serde(skip_serializing_if = "Option::is_none")]
+        branches: Option<Vec<String>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tags:     Option<Vec<String>>,
+    },
+
+    // Commits the reverse of another commit's changes on top of "parent", like `git revert`
+    #[serde(rename = "revert")]
+    Revert {
+        id: String,
+
+        // The commit whose changes are reversed, and the new commit's parent
+        commit: String,
+        parent: String,
+
+        // Defaults to Git's message: "Revert ...", and "This reverts commit ..."
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+        // The contents of conflicting paths (or null to delete them)
+        #[serde(skip_serializing_if = "Option::is_none", serialize_with = "sorted_tree")]
+        resolutions: Option<HashMap<String, TreeNode>>,
+
+        #[serde(skip_serializing_if = "Option::is_none")]
+        author:         Option<Identity>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        committer:      Option<Identity>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        author_date:    Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        committer_date: Option<String>,
+
+        #[serde(skip_serializing_if = "Option::is_none")]
+        branches: Option<Vec<String>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tags:     Option<Vec<String>>,
+    },
+
     #[serde(rename = "branch")]
     Branch {
         name: String,
@@ -65,10 +337,52 @@ pub enum Command {
         name: String,
         on: String,
 
-        #[serde(default = "false_boolean")]
+        // Only used for annotated tags
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tagger: Option<Identity>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+
+        #[serde(default = "false_boolean", skip_serializing_if = "is_false")]
         lightweight: bool
     },
 
+    // Points HEAD at a branch, e.g. { "branch": "feature" }, or detaches it at a commit, e.g. { "on": "b" }.
+    // The branch doesn't need to exist yet. Non-bare repositories check out HEAD after the last command.
+    #[serde(rename = "head")]
+    Head {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        branch: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        on: Option<String>,
+    },
+
+    // Makes uncommitted changes, after HEAD is checked out (non-bare repositories only).
+    // Paths are applied in this order: staged, unstaged, untracked, ignored.
+    #[serde(rename = "worktree")]
+    Worktree {
+        // Written to the index and the work tree. null deletes the path from both.
+        #[serde(skip_serializing_if = "Option::is_none", serialize_with = "sorted_tree")]
+        staged: Option<HashMap<String, TreeNode>>,
+
+        // Written to the work tree only. null deletes the file, and leaves it in the index.
+        #[serde(skip_serializing_if = "Option::is_none", serialize_with = "sorted_tree")]
+        unstaged: Option<HashMap<String, TreeNode>>,
+
+        // Written to the work tree. The paths must not be in the index.
+        #[serde(skip_serializing_if = "Option::is_none", serialize_with = "sorted_tree")]
+        untracked: Option<HashMap<String, TreeNode>>,
+
+        // Untracked files that are also excluded, in .git/info/exclude
+        #[serde(skip_serializing_if = "Option::is_none", serialize_with = "sorted_tree")]
+        ignored: Option<HashMap<String, TreeNode>>,
+    },
+
+    // Leaves a rebase, cherry-pick, revert or bisect in progress, after HEAD is checked out
+    // (non-bare repositories only), e.g. { "type": "state", "state": "cherry-pick", "commit": "c" }
+    #[serde(rename = "state")]
+    State(OperationState),
+
     #[serde(rename = "config")]
     Config {
       all_name:       Option<String>,   all_email:       Option<String>,
@@ -76,6 +390,14 @@ pub enum Command {
       committer_name: Option<String>,   committer_email: Option<String>,
       tagger_name:    Option<String>,   tagger_email:    Option<String>,
 
+      // Starts a deterministic clock at this date, e.g. "1500000000 +0200"
+      date: Option<String>,
+      // Seconds between each generated date
+      date_increment: Option<i64>,
+
       tree: Option<HashMap<String, TreeNode>>,
+
+      // If true, commit trees are patches on top of the first parent's tree
+      incremental: Option<bool>,
     }
 }
\ No newline at end of file
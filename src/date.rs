@@ -0,0 +1,128 @@
+use git2::Time;
+
+use super::error::Error;
+
+// Parses a date in Git's internal format: "<unix timestamp> <timezone offset>"
+// e.g. "1500000000 +0200" or "1500000000 -0730".
+// The timezone offset can be omitted, in which case it's UTC ("+0000").
+pub fn parse_date(date: &str) -> Result<Time, Error> {
+    let invalid = || Error::InvalidValue(format!("Invalid date (expected \"<seconds> <+hhmm>\"): {}", date));
+
+    let mut parts = date.split_whitespace();
+
+    let seconds: i64 = match parts.next() {
+        Some(seconds) => seconds.parse().map_err(|_| invalid())?,
+        None => return Err(invalid())
+    };
+
+    let offset_minutes = match parts.next() {
+        Some(offset) => parse_offset(offset).ok_or_else(invalid)?,
+        None => 0
+    };
+
+    if parts.next().is_some() {
+        return Err(invalid());
+    }
+
+    Ok(Time::new(seconds, offset_minutes))
+}
+
+// The inverse of parse_date, e.g. "1500000000 +0200"
+pub fn format_date(time: Time) -> String {
+    let offset = time.offset_minutes();
+    let sign = if offset < 0 { '-' } else { '+' };
+
+    format!("{} {}{:02}{:02}", time.seconds(), sign, offset.abs() / 60, offset.abs() % 60)
+}
+
+// "+0200" => 120, "-0730" => -450
+fn parse_offset(offset: &str) -> Option<i32> {
+    let (sign, digits) = match offset.chars().next() {
+        Some('+') => (1, &offset[1..]),
+        Some('-') => (-1, &offset[1..]),
+        _ => return None
+    };
+
+    if digits.len() != 4 || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let hours: i32 = digits[0..2].parse().ok()?;
+    let minutes: i32 = digits[2..4].parse().ok()?;
+
+    if minutes >= 60 {
+        return None;
+    }
+
+    Some(sign * (hours * 60 + minutes))
+}
+
+// Hands out deterministic dates, so that generated commits have stable hashes.
+// Every call to `tick` returns the current date and advances it by `increment` seconds.
+pub struct Clock {
+    next: Time,
+    increment: i64,
+}
+
+impl Clock {
+    pub fn new(start: Time, increment: i64) -> Clock {
+        Clock { next: start, increment }
+    }
+
+    pub fn set_increment(&mut self, increment: i64) {
+        self.increment = increment;
+    }
+
+    pub fn tick(&mut self) -> Time {
+        let time = self.next;
+        self.next = Time::new(time.seconds() + self.increment, time.offset_minutes());
+        time
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_date(date: &str, seconds: i64, offset_minutes: i32) {
+        let time = parse_date(date).unwrap();
+        assert_eq!(time.seconds(), seconds);
+        assert_eq!(time.offset_minutes(), offset_minutes);
+    }
+
+    #[test]
+    fn parse_date_test() {
+        assert_date("1500000000 +0000", 1500000000, 0);
+        assert_date("1500000000 +0200", 1500000000, 120);
+        assert_date("1500000000 -0730", 1500000000, -450);
+        assert_date("1500000000", 1500000000, 0);
+        assert_date("  0   +0100 ", 0, 60);
+
+        assert!(parse_date("").is_err());
+        assert!(parse_date("yesterday").is_err());
+        assert!(parse_date("1500000000 0200").is_err());
+        assert!(parse_date("1500000000 +02:00").is_err());
+        assert!(parse_date("1500000000 +0260").is_err());
+        assert!(parse_date("1500000000 +0200 extra").is_err());
+    }
+
+    #[test]
+    fn format_date_test() {
+        assert_eq!(format_date(Time::new(1500000000, 0)), "1500000000 +0000");
+        assert_eq!(format_date(Time::new(1500000000, 120)), "1500000000 +0200");
+        assert_eq!(format_date(Time::new(1500000000, -450)), "1500000000 -0730");
+    }
+
+    #[test]
+    fn clock_test() {
+        let mut clock = Clock::new(Time::new(1000, 60), 30);
+
+        let a = clock.tick();
+        let b = clock.tick();
+        let c = clock.tick();
+
+        assert_eq!((a.seconds(), a.offset_minutes()), (1000, 60));
+        assert_eq!((b.seconds(), b.offset_minutes()), (1030, 60));
+        assert_eq!((c.seconds(), c.offset_minutes()), (1060, 60));
+    }
+}
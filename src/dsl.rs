@@ -0,0 +1,554 @@
+// A compact, line-oriented language that compiles to a list of commands.
+//
+//     # Comments start with a hash
+//     config incremental
+//     a: "Initial commit"
+//       README: "Hello\n"
+//     b < a: "Commit B" [branch feature]
+//       src/main.rs: "fn main() {}\n"
+//     c < a: "Commit C"
+//       - README
+//     d = merge b c: "Merge B and C" [tag 1.0.0]
+//     branch master -> d
+//     tag v1 -> a
+//
+// Indented lines add files to the tree of the commit or merge above them ("path: contents"),
+// or delete paths from it ("- path").
+
+use std::collections::HashMap;
+
+use super::command::{Command, TreeNode, MergeStrategy};
+use super::error::Error;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+    Less,
+    Equals,
+    Colon,
+    Comma,
+    Arrow,
+    OpenBracket,
+    CloseBracket,
+}
+
+fn describe(token: Option<&Token>) -> String {
+    match token {
+        Some(Token::Word(word)) => format!("`{}`", word),
+        Some(Token::Str(s)) => format!("{:?}", s),
+        Some(Token::Less) => "`<`".to_string(),
+        Some(Token::Equals) => "`=`".to_string(),
+        Some(Token::Colon) => "`:`".to_string(),
+        Some(Token::Comma) => "`,`".to_string(),
+        Some(Token::Arrow) => "`->`".to_string(),
+        Some(Token::OpenBracket) => "`[`".to_string(),
+        Some(Token::CloseBracket) => "`]`".to_string(),
+        None => "end of line".to_string(),
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    !c.is_whitespace() && !"<=:,[]\"#".contains(c)
+}
+
+fn tokenize(line: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = line.chars().collect();
+    let is_arrow = |i: usize| chars[i] == '-' && chars.get(i + 1) == Some(&'>');
+
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let symbol = match c {
+            '<' => Some(Token::Less),
+            '=' => Some(Token::Equals),
+            ':' => Some(Token::Colon),
+            ',' => Some(Token::Comma),
+            '[' => Some(Token::OpenBracket),
+            ']' => Some(Token::CloseBracket),
+            _ => None
+        };
+
+        if c == '#' {
+            break;
+        } else if c.is_whitespace() {
+            i += 1;
+        } else if let Some(symbol) = symbol {
+            tokens.push(symbol);
+            i += 1;
+        } else if is_arrow(i) {
+            tokens.push(Token::Arrow);
+            i += 2;
+        } else if c == '"' {
+            let mut s = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    Some('"') => break,
+                    Some('\\') => {
+                        match chars.get(i + 1) {
+                            Some('n') => s.push('\n'),
+                            Some('t') => s.push('\t'),
+                            Some('\\') => s.push('\\'),
+                            Some('"') => s.push('"'),
+                            Some(other) => return Err(format!("Unknown escape sequence: \\{}", other)),
+                            None => return Err("Unterminated string".to_string()),
+                        }
+                        i += 1;
+                    },
+                    Some(&other) => s.push(other),
+                    None => return Err("Unterminated string".to_string()),
+                }
+                i += 1;
+            }
+            tokens.push(Token::Str(s));
+            i += 1;
+        } else {
+            // Words end at whitespace, symbols, or an arrow (e.g. "master->a")
+            let start = i;
+            while i < chars.len() && is_word_char(chars[i]) && !is_arrow(i) {
+                i += 1;
+            }
+            tokens.push(Token::Word(chars[start..i].iter().collect()));
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Line {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Line {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        if self.peek() == Some(token) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: &Token) -> Result<(), String> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(format!("Expected {}, found {}", describe(Some(token)), describe(self.peek())))
+        }
+    }
+
+    fn word(&mut self, what: &str) -> Result<String, String> {
+        match self.next() {
+            Some(Token::Word(word)) => Ok(word),
+            other => Err(format!("Expected {}, found {}", what, describe(other.as_ref())))
+        }
+    }
+
+    fn string(&mut self, what: &str) -> Result<String, String> {
+        match self.next() {
+            Some(Token::Str(s)) => Ok(s),
+            other => Err(format!("Expected {} (a quoted string), found {}", what, describe(other.as_ref())))
+        }
+    }
+
+    fn end(&self) -> Result<(), String> {
+        match self.peek() {
+            None => Ok(()),
+            other => Err(format!("Unexpected {}", describe(other)))
+        }
+    }
+}
+
+// Branches, tags and flags in square brackets, e.g. "[branch master, tag 1.0.0, no-ff]"
+#[derive(Default)]
+struct Attributes {
+    branches: Vec<String>,
+    tags: Vec<String>,
+    no_ff: bool,
+    in_progress: bool,
+}
+
+fn parse_attributes(line: &mut Line, merge: bool) -> Result<Attributes, String> {
+    let mut attributes = Attributes::default();
+
+    while line.eat(&Token::OpenBracket) {
+        loop {
+            match line.word("`branch`, `tag`, `no-ff` or `in-progress`")?.as_str() {
+                "branch" => attributes.branches.push(line.word("a branch name")?),
+                "tag" => attributes.tags.push(line.word("a tag name")?),
+                "no-ff" if merge => attributes.no_ff = true,
+                "in-progress" if merge => attributes.in_progress = true,
+                other => return Err(format!("Unknown attribute: {}", other))
+            }
+            if !line.eat(&Token::Comma) {
+                break;
+            }
+        }
+        line.expect(&Token::CloseBracket)?;
+    }
+
+    Ok(attributes)
+}
+
+fn optional(values: Vec<String>) -> Option<Vec<String>> {
+    if values.is_empty() { None } else { Some(values) }
+}
+
+// An optional message, e.g. `: "Commit message"`
+fn parse_message(line: &mut Line) -> Result<Option<String>, String> {
+    if line.eat(&Token::Colon) {
+        Ok(Some(line.string("a message")?))
+    } else {
+        Ok(None)
+    }
+}
+
+fn parse_statement(line: &mut Line) -> Result<Command, String> {
+    let first = line.word("a commit id, `branch`, `tag`, `head` or `config`")?;
+
+    match (first.as_str(), line.peek()) {
+        ("branch", Some(Token::Word(_))) => {
+            let name = line.word("a branch name")?;
+            line.expect(&Token::Arrow)?;
+            let on = line.word("a commit id")?;
+            line.end()?;
+            Ok(Command::Branch { name, on })
+        },
+        ("tag", Some(Token::Word(_))) => {
+            let name = line.word("a tag name")?;
+            line.expect(&Token::Arrow)?;
+            let on = line.word("a commit id")?;
+            line.end()?;
+            Ok(Command::Tag { name, on, tagger: None, message: None, lightweight: false })
+        },
+        // "head branch feature", or a detached "head -> b"
+        ("head", Some(Token::Word(_))) => {
+            let keyword = line.word("`branch`")?;
+            if keyword != "branch" {
+                return Err(format!("Expected `branch` or `->`, found `{}`", keyword));
+            }
+            let branch = line.word("a branch name")?;
+            line.end()?;
+            Ok(Command::Head { branch: Some(branch), on: None })
+        },
+        ("head", Some(Token::Arrow)) => {
+            line.next();
+            let on = line.word("a commit id")?;
+            line.end()?;
+            Ok(Command::Head { branch: None, on: Some(on) })
+        },
+        ("config", Some(Token::Word(_))) => {
+            let mut config = empty_config();
+            if let Command::Config { incremental, date, .. } = &mut config {
+                match line.word("a setting")?.as_str() {
+                    "incremental" => *incremental = Some(true),
+                    "date" => *date = Some(line.string("a date")?),
+                    other => return Err(format!("Unknown setting (expected incremental or date): {}", other))
+                }
+            }
+            line.end()?;
+            Ok(config)
+        },
+        (_, Some(Token::Equals)) => {
+            line.next();
+            let keyword = line.word("`merge`")?;
+            if keyword != "merge" {
+                return Err(format!("Expected `merge`, found `{}`", keyword));
+            }
+
+            let mut commits = Vec::new();
+            while let Some(Token::Word(_)) = line.peek() {
+                commits.push(line.word("a commit id")?);
+                line.eat(&Token::Comma);
+            }
+            if commits.is_empty() {
+                return Err("Expected the commit ids to merge".to_string());
+            }
+
+            let message = parse_message(line)?;
+            let attributes = parse_attributes(line, true)?;
+            line.end()?;
+
+            Ok(Command::Merge {
+                id: first,
+                commits,
+                message,
+                tree: None,
+                delete: Vec::new(),
+                incremental: None,
+                author: None,
+                committer: None,
+                author_date: None,
+                committer_date: None,
+                branches: optional(attributes.branches),
+                tags: optional(attributes.tags),
+                // Replaced with a tree if the merge lists any files. In-progress merges always merge the contents.
+                strategy: if attributes.in_progress { None } else { Some(MergeStrategy::Recursive) },
+                resolutions: None,
+                no_ff: attributes.no_ff,
+                in_progress: attributes.in_progress,
+            })
+        },
+        _ => {
+            let mut parents = Vec::new();
+            if line.eat(&Token::Less) {
+                while let Some(Token::Word(_)) = line.peek() {
+                    parents.push(line.word("a parent id")?);
+                    line.eat(&Token::Comma);
+                }
+                if parents.is_empty() {
+                    return Err(format!("Expected a parent id, found {}", describe(line.peek())));
+                }
+            }
+
+            let message = parse_message(line)?;
+            let attributes = parse_attributes(line, false)?;
+            line.end()?;
+
+            Ok(Command::Commit {
+                id: first,
+                message,
+                parents,
+                tree: None,
+                delete: Vec::new(),
+                incremental: None,
+                author: None,
+                committer: None,
+                author_date: None,
+                committer_date: None,
+                branches: optional(attributes.branches),
+                tags: optional(attributes.tags),
+            })
+        }
+    }
+}
+
+fn empty_config() -> Command {
+    Command::Config {
+        all_name: None, all_email: None,
+        author_name: None, author_email: None,
+        committer_name: None, committer_email: None,
+        tagger_name: None, tagger_email: None,
+        date: None,
+        date_increment: None,
+        tree: None,
+        incremental: None,
+    }
+}
+
+// An indented line, e.g. `README: "Hello"` or `- README`
+fn parse_file(line: &mut Line, command: Option<&mut Command>) -> Result<(), String> {
+    let (tree, delete, strategy) = match command {
+        Some(Command::Commit { tree, delete, .. }) => (tree, delete, None),
+        Some(Command::Merge { tree, delete, strategy, .. }) => (tree, delete, Some(strategy)),
+        _ => return Err("Indented lines must follow a commit or merge".to_string())
+    };
+
+    // A merge that lists its files, or deletes them, doesn't merge the parents' trees
+    if let Some(strategy) = strategy {
+        *strategy = None;
+    }
+
+    if line.peek() == Some(&Token::Word("-".to_string())) {
+        line.next();
+        let path = match line.next() {
+            Some(Token::Word(path)) | Some(Token::Str(path)) => path,
+            other => return Err(format!("Expected a path, found {}", describe(other.as_ref())))
+        };
+        line.end()?;
+        delete.push(path);
+        return Ok(());
+    }
+
+    let path = match line.next() {
+        Some(Token::Word(path)) | Some(Token::Str(path)) => path,
+        other => return Err(format!("Expected a path, found {}", describe(other.as_ref())))
+    };
+    line.expect(&Token::Colon)?;
+    let contents = line.string("the file contents")?;
+    line.end()?;
+
+    let tree = tree.get_or_insert_with(HashMap::new);
+    if tree.contains_key(&path) {
+        return Err(format!("File already exists: {}", path));
+    }
+    tree.insert(path, TreeNode::Utf8File(contents));
+
+    Ok(())
+}
+
+pub fn parse_dsl(source: &str) -> Result<Vec<Command>, Error> {
+    let mut commands: Vec<Command> = Vec::new();
+
+    for (index, text) in source.lines().enumerate() {
+        let error = |message: String| Error::Dsl { line: index + 1, message };
+
+        let mut line = Line { tokens: tokenize(text).map_err(error)?, position: 0 };
+        if line.tokens.is_empty() {
+            continue;
+        }
+
+        let indented = text.starts_with(|c: char| c.is_whitespace());
+        if indented {
+            parse_file(&mut line, commands.last_mut()).map_err(error)?;
+        } else {
+            commands.push(parse_statement(&mut line).map_err(error)?);
+        }
+    }
+
+    Ok(commands)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokenize_test() {
+        assert_eq!(tokenize(r#"b < a: "Commit \"B\"\n" # comment"#).unwrap(), vec![
+            Token::Word("b".to_string()),
+            Token::Less,
+            Token::Word("a".to_string()),
+            Token::Colon,
+            Token::Str("Commit \"B\"\n".to_string()),
+        ]);
+        assert_eq!(tokenize("branch my-branch->a").unwrap(), vec![
+            Token::Word("branch".to_string()),
+            Token::Word("my-branch".to_string()),
+            Token::Arrow,
+            Token::Word("a".to_string()),
+        ]);
+        assert!(tokenize(r#"a: "unterminated"#).is_err());
+    }
+
+    #[test]
+    fn parse_dsl_test() {
+        let commands = parse_dsl(r#"
+config incremental
+a: "Initial" [branch dev]
+  README: "Hello\n"
+b < a: "Commit B"
+  - README
+c < a
+d = merge b c: "Merge" [tag 1.0.0, no-ff]
+e = merge b, c
+  README: "Resolved"
+branch master -> e
+tag v1 -> a
+"#).unwrap();
+
+        assert_eq!(commands.len(), 8);
+
+        let heads = parse_dsl("a\nhead branch feature\nhead -> a\n").unwrap();
+        assert!(matches!(&heads[1], Command::Head { branch: Some(branch), on: None } if branch == "feature"));
+        assert!(matches!(&heads[2], Command::Head { branch: None, on: Some(on) } if on == "a"));
+
+        match &commands[1] {
+            Command::Commit { id, message, parents, tree, branches, .. } => {
+                assert_eq!(id, "a");
+                assert_eq!(message.as_deref(), Some("Initial"));
+                assert!(parents.is_empty());
+                assert!(matches!(tree.as_ref().unwrap().get("README"), Some(TreeNode::Utf8File(s)) if s == "Hello\n"));
+                assert_eq!(branches, &Some(vec!["dev".to_string()]));
+            },
+            other => panic!("Expected a commit: {:?}", other)
+        }
+        match &commands[2] {
+            Command::Commit { parents, delete, .. } => {
+                assert_eq!(parents, &vec!["a".to_string()]);
+                assert_eq!(delete, &vec!["README".to_string()]);
+            },
+            other => panic!("Expected a commit: {:?}", other)
+        }
+        match &commands[4] {
+            Command::Merge { commits, tags, no_ff, strategy, tree, .. } => {
+                assert_eq!(commits, &vec!["b".to_string(), "c".to_string()]);
+                assert_eq!(tags, &Some(vec!["1.0.0".to_string()]));
+                assert!(*no_ff);
+                assert!(strategy.is_some());
+                assert!(tree.is_none());
+            },
+            other => panic!("Expected a merge: {:?}", other)
+        }
+        match &commands[5] {
+            Command::Merge { strategy, tree, .. } => {
+                assert!(strategy.is_none());
+                assert!(tree.is_some());
+            },
+            other => panic!("Expected a merge: {:?}", other)
+        }
+        assert!(matches!(&commands[6], Command::Branch { name, on } if name == "master" && on == "e"));
+        assert!(matches!(&commands[7], Command::Tag { name, on, lightweight: false, .. } if name == "v1" && on == "a"));
+    }
+
+    #[test]
+    fn merge_delete_test() {
+        let commands = parse_dsl(r#"
+config incremental
+a
+  x: "X"
+  y: "Y"
+b < a
+c < a
+d = merge b c
+  - x
+"#).unwrap();
+
+        // Only deleting files also replaces the content merge
+        assert!(matches!(&commands[4], Command::Merge { strategy: None, delete, .. } if delete == &vec!["x".to_string()]));
+
+        let dir = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init_bare(dir.path()).unwrap();
+        let mut interpreter = super::super::interpreter::Interpreter::new(&repo).unwrap();
+        interpreter.interpret_commands(&commands).unwrap();
+
+        let d = repo.find_commit(interpreter.id_to_oid_lookup()["d"]).unwrap();
+        let tree = d.tree().unwrap();
+        assert!(tree.get_name("x").is_none());
+        assert!(tree.get_name("y").is_some());
+    }
+
+    #[test]
+    fn in_progress_test() {
+        let commands = parse_dsl(r#"
+a
+b < a [branch master]
+c < a
+d = merge b c: "Merge c" [in-progress]
+"#).unwrap();
+
+        assert!(matches!(&commands[3], Command::Merge { in_progress: true, strategy: None, tree: None, message: Some(message), .. } if message == "Merge c"));
+
+        // Only merges can be left in progress
+        assert!(parse_dsl("a [in-progress]").is_err());
+    }
+
+    #[test]
+    fn parse_dsl_error_test() {
+        let line_of = |source: &str| match parse_dsl(source) {
+            Err(Error::Dsl { line, .. }) => line,
+            other => panic!("Expected a DSL error: {:?}", other)
+        };
+
+        assert_eq!(line_of("a\nb < : \"B\""), 2);
+        assert_eq!(line_of("  README: \"Hello\""), 1);
+        assert_eq!(line_of("a\n\nb = merge"), 3);
+        assert_eq!(line_of("a [color red]"), 1);
+        assert_eq!(line_of("a: B"), 1);
+        assert_eq!(line_of("a\n  README: \"x\"\n  README: \"y\""), 3);
+    }
+}
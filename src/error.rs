@@ -0,0 +1,91 @@
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    // A commit id that wasn't created by an earlier command
+    UnknownId(String),
+
+    // A commit id that was already created by an earlier command
+    DuplicateId(String),
+
+    // The same path was provided more than once in a tree
+    DuplicatePath(String),
+
+    // A path that's absolute, or has an empty, ".", ".." or ".git" component
+    InvalidPath(String),
+
+    // A path was provided as a file, and also as a directory containing other paths
+    FileDirectoryConflict(String),
+
+    // A merge command with an empty list of commits
+    EmptyMerge,
+
+    // A merge with conflicting paths that don't have resolutions
+    MergeConflict(Vec<String>),
+
+    // A malformed value in a command, e.g. an invalid date or file mode
+    InvalidValue(String),
+
+    // A branch or tag name that Git doesn't allow, e.g. "my..branch"
+    InvalidRefName(String),
+
+    // The input couldn't be parsed. Includes the line and column.
+    Json(serde_json::Error),
+    Yaml(serde_norway::Error),
+    Toml(toml::de::Error),
+    Dsl { line: usize, message: String },
+    FastExport { line: usize, message: String },
+
+    // Reading or writing a file failed
+    Io(String, io::Error),
+
+    Git(git2::Error),
+
+    // Wraps an error with the index of the command that caused it
+    Command { index: usize, error: Box<Error> },
+}
+
+impl Error {
+    pub fn in_command(self, index: usize) -> Error {
+        Error::Command { index, error: Box::new(self) }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnknownId(id) => write!(f, "ID doesn't exist: {}", id),
+            Error::DuplicateId(id) => write!(f, "ID already exists: {}", id),
+            Error::DuplicatePath(path) => write!(f, "File or directory already exists: {}", path),
+            Error::InvalidPath(path) => write!(f, "Invalid path: {}", path),
+            Error::FileDirectoryConflict(path) => write!(f, "Already added as a non-directory: {}", path),
+            Error::EmptyMerge => write!(f, "Commits cannot be empty"),
+            Error::MergeConflict(paths) => write!(f, "Merge conflicts without resolutions: {}", paths.join(", ")),
+            Error::InvalidValue(message) => write!(f, "{}", message),
+            Error::InvalidRefName(name) => write!(f, "Invalid branch or tag name: {}", name),
+            Error::Json(e) => write!(f, "Invalid input: {}", e),
+            Error::Yaml(e) => write!(f, "Invalid input: {}", e),
+            Error::Toml(e) => write!(f, "Invalid input: {}", e),
+            Error::Dsl { line, message } => write!(f, "Invalid input: {} at line {}", message, line),
+            Error::FastExport { line, message } => write!(f, "Invalid input: {} at line {}", message, line),
+            Error::Io(path, e) => write!(f, "{}: {}", path, e),
+            Error::Git(e) => write!(f, "{}", e),
+            Error::Command { index, error } => write!(f, "command at index {}: {}", index, error),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<git2::Error> for Error {
+    fn from(e: git2::Error) -> Error {
+        Error::Git(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        Error::Json(e)
+    }
+}
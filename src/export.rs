@@ -0,0 +1,363 @@
+// Converts an existing repository back into a list of commands.
+// Feeding the commands to an Interpreter reproduces the same commit graph, branches and tags.
+// If the contents and identities are kept, the commits even get the same hashes.
+
+use git2::{Repository, Oid, Commit, Tree, Signature, BranchType, ObjectType};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use super::command::{Command, TreeNode, Identity, GitlinkTarget};
+use super::date::format_date;
+use super::error::Error;
+use super::interpreter::{MODE_EXECUTABLE, MODE_SYMLINK, MODE_GITLINK};
+
+#[derive(Debug, Clone, Default)]
+pub struct ExportOptions {
+    // Replaces the contents of every file with an empty string. Paths, modes, symlinks and gitlinks are kept.
+    pub drop_contents: bool,
+
+    // Replaces names and emails with "Person 1" <person1@example.com>, etc.
+    pub anonymize: bool,
+
+    // Only exports commits that are at most this many commits away from a branch or tag.
+    // Parents that are further away are dropped, so the oldest exported commits become root commits.
+    pub depth: Option<usize>,
+}
+
+struct Exporter<'a> {
+    repo: &'a Repository,
+    options: &'a ExportOptions,
+
+    // Commit hash => command id
+    ids: HashMap<Oid, String>,
+    // The command ids that are taken
+    taken: HashSet<String>,
+
+    // (name, email) => anonymized (name, email)
+    identities: HashMap<(String, String), (String, String)>,
+}
+
+impl<'a> Exporter<'a> {
+    // Short hashes are easier to read. The full hash is only used if the short one is taken.
+    fn assign_id(&mut self, oid: Oid) -> String {
+        let full = oid.to_string();
+        let short = full[..7].to_string();
+
+        let id = if self.taken.contains(&short) { full } else { short };
+        self.taken.insert(id.clone());
+        self.ids.insert(oid, id.clone());
+        id
+    }
+
+    fn identity(&mut self, signature: &Signature) -> Identity {
+        let name = String::from_utf8_lossy(signature.name_bytes()).into_owned();
+        let email = String::from_utf8_lossy(signature.email_bytes()).into_owned();
+
+        let (name, email) = if self.options.anonymize {
+            let count = self.identities.len();
+            self.identities.entry((name, email)).or_insert_with(|| {
+                (format!("Person {}", count + 1), format!("person{}@example.com", count + 1))
+            }).clone()
+        } else {
+            (name, email)
+        };
+
+        Identity { name: Some(name), email: Some(email), date: Some(format_date(signature.when())) }
+    }
+
+    fn blob_node(&self, contents: &[u8], executable: bool) -> TreeNode {
+        if self.options.drop_contents {
+            return TreeNode::Utf8 { utf8: String::new(), mode: None, executable };
+        }
+
+        match std::str::from_utf8(contents) {
+            Ok(utf8) if !executable => TreeNode::Utf8File(utf8.to_string()),
+            Ok(utf8) => TreeNode::Utf8 { utf8: utf8.to_string(), mode: None, executable },
+            Err(_) => TreeNode::Base64 { base64: encode_base64(contents), mode: None, executable }
+        }
+    }
+
+    fn add_tree(&self, tree: &Tree, prefix: &str, nodes: &mut HashMap<String, TreeNode>) -> Result<(), Error> {
+        for entry in tree.iter() {
+            let name = String::from_utf8_lossy(entry.name_bytes());
+            let path = format!("{}{}", prefix, name);
+
+            let node = match (entry.kind(), entry.filemode()) {
+                (Some(ObjectType::Tree), _) => {
+                    let subtree = self.repo.find_tree(entry.id())?;
+                    self.add_tree(&subtree, &format!("{}/", path), nodes)?;
+                    continue;
+                },
+                (_, MODE_GITLINK) => TreeNode::Gitlink { gitlink: GitlinkTarget::Hash(entry.id().to_string()), url: None },
+                (_, MODE_SYMLINK) => {
+                    let blob = self.repo.find_blob(entry.id())?;
+                    TreeNode::Symlink { symlink: String::from_utf8_lossy(blob.content()).into_owned() }
+                },
+                (_, mode) => {
+                    let blob = self.repo.find_blob(entry.id())?;
+                    self.blob_node(blob.content(), mode == MODE_EXECUTABLE)
+                }
+            };
+
+            nodes.insert(path, node);
+        }
+
+        Ok(())
+    }
+
+    fn commit_command(&mut self, commit: &Commit, included: &HashSet<Oid>) -> Result<Command, Error> {
+        let parents: Vec<String> = commit.parent_ids()
+            .filter(|oid| included.contains(oid))
+            .map(|oid| self.ids[&oid].clone())
+            .collect();
+
+        let mut tree = HashMap::new();
+        self.add_tree(&commit.tree()?, "", &mut tree)?;
+
+        let id = self.assign_id(commit.id());
+        let message = Some(utf8_message(commit.message_bytes(), commit.message_encoding(), commit.id())?);
+        let author = Some(self.identity(&commit.author()));
+        let committer = Some(self.identity(&commit.committer()));
+
+        let command = if parents.len() > 1 {
+            Command::Merge {
+                id,
+                commits: parents,
+                message,
+                tree: Some(tree),
+                delete: Vec::new(),
+                incremental: Some(false),
+                author,
+                committer,
+                author_date: None,
+                committer_date: None,
+                branches: None,
+                tags: None,
+                strategy: None,
+                resolutions: None,
+                no_ff: true,
+                in_progress: false,
+            }
+        } else {
+            Command::Commit {
+                id,
+                message,
+                parents,
+                tree: Some(tree),
+                delete: Vec::new(),
+                incremental: Some(false),
+                author,
+                committer,
+                author_date: None,
+                committer_date: None,
+                branches: None,
+                tags: None,
+            }
+        };
+
+        Ok(command)
+    }
+}
+
+// Messages are kept as they are, so that the commits get the same hashes. Commands can only hold UTF-8.
+fn utf8_message(bytes: &[u8], encoding: Option<&str>, object: Oid) -> Result<String, Error> {
+    if let Some(encoding) = encoding {
+        if !encoding.eq_ignore_ascii_case("utf-8") && !encoding.eq_ignore_ascii_case("utf8") {
+            return Err(Error::InvalidValue(format!("Unsupported encoding (only UTF-8 is supported) in {}: {}", object, encoding)));
+        }
+    }
+    match std::str::from_utf8(bytes) {
+        Ok(message) => Ok(message.to_string()),
+        Err(_) => Err(Error::InvalidValue(format!("Messages must be UTF-8: {}", object)))
+    }
+}
+
+fn encode_base64(contents: &[u8]) -> String {
+    use base64::Engine;
+
+    base64::engine::general_purpose::STANDARD.encode(contents)
+}
+
+// The commits that are within `depth` commits of a tip (a tip has a depth of 1)
+fn commits_within_depth(repo: &Repository, tips: &[Oid], depth: usize) -> Result<HashSet<Oid>, Error> {
+    let mut included = HashSet::new();
+    let mut queue: VecDeque<(Oid, usize)> = tips.iter().map(|oid| (*oid, 1)).collect();
+
+    while let Some((oid, commit_depth)) = queue.pop_front() {
+        if commit_depth > depth || !included.insert(oid) {
+            continue;
+        }
+        for parent_oid in repo.find_commit(oid)?.parent_ids() {
+            queue.push_back((parent_oid, commit_depth + 1));
+        }
+    }
+
+    Ok(included)
+}
+
+pub fn export_repository(repo: &Repository, options: &ExportOptions) -> Result<Vec<Command>, Error> {
+    let mut exporter = Exporter { repo, options, ids: HashMap::new(), taken: HashSet::new(), identities: HashMap::new() };
+
+    // (name, commit hash), sorted by name so that the output is stable
+    let mut branches: Vec<(String, Oid)> = Vec::new();
+    for branch in repo.branches(Some(BranchType::Local))? {
+        let (branch, _) = branch?;
+        if let Some(name) = branch.name()? {
+            branches.push((name.to_string(), branch.get().peel(ObjectType::Commit)?.id()));
+        }
+    }
+    branches.sort();
+
+    // (name, commit hash, tagger, message). Tags that don't point to a commit are skipped.
+    let mut tags: Vec<(String, Oid, Option<Signature<'static>>, Option<String>)> = Vec::new();
+    for name in repo.tag_names(None)?.iter().flatten() {
+        let object = repo.revparse_single(&format!("refs/tags/{}", name))?;
+        let commit = match object.peel(ObjectType::Commit) {
+            Ok(commit) => commit,
+            Err(_) => continue
+        };
+        let tagger = object.as_tag().and_then(|tag| tag.tagger().map(|tagger| tagger.to_owned()));
+        let message = match object.as_tag().and_then(|tag| tag.message_bytes()) {
+            Some(message) => Some(utf8_message(message, None, object.id())?),
+            None => None
+        };
+        tags.push((name.to_string(), commit.id(), tagger, message));
+    }
+    tags.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let tips: Vec<Oid> = branches.iter().map(|(_, oid)| *oid)
+        .chain(tags.iter().map(|(_, oid, _, _)| *oid))
+        .collect();
+
+    // Parents come before their children
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE);
+    for oid in &tips {
+        revwalk.push(*oid)?;
+    }
+    let all_oids: Vec<Oid> = revwalk.collect::<Result<_, _>>()?;
+
+    let included: HashSet<Oid> = match options.depth {
+        Some(depth) => commits_within_depth(repo, &tips, depth)?,
+        None => all_oids.iter().cloned().collect()
+    };
+
+    let mut commands = Vec::new();
+
+    for oid in all_oids.iter().filter(|oid| included.contains(oid)) {
+        let commit = repo.find_commit(*oid)?;
+        commands.push(exporter.commit_command(&commit, &included)?);
+    }
+
+    for (name, oid) in branches {
+        commands.push(Command::Branch { name, on: exporter.ids[&oid].clone() });
+    }
+
+    for (name, oid, tagger, message) in tags {
+        let on = exporter.ids[&oid].clone();
+        let command = match tagger {
+            Some(tagger) => Command::Tag { name, on, tagger: Some(exporter.identity(&tagger)), message, lightweight: false },
+            None => Command::Tag { name, on, tagger: None, message: None, lightweight: true }
+        };
+        commands.push(command);
+    }
+
+    Ok(commands)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::builder::{RepoBuilder, GeneratedRepo};
+
+    fn build_fixture(path: &std::path::Path) -> Result<GeneratedRepo, Error> {
+        RepoBuilder::new(path).bare(true).date("1500000000 +0200")
+            .commit("a").message("Initial commit").file("README", "Hello").binary_file("data.bin", &[0, 159, 255])
+            .commit("b").parents(["a"]).file("README", "Hello B").executable_file("run.sh", "#!/bin/sh").branch("feature")
+            .commit("c").parents(["a"]).file("README", "Hello C").symlink("link", "README").author("Someone", "someone@example.com")
+            .merge("d", ["b", "c"]).message("Merge").file("README", "Merged").branch("master")
+            .finish().command(Command::Tag { name: "1.0.0".to_string(), on: "d".to_string(), tagger: None, message: Some("Release 1.0.0\n".to_string()), lightweight: false })
+            .build()
+    }
+
+    fn import(path: std::path::PathBuf, commands: Vec<Command>) -> GeneratedRepo {
+        let builder = commands.into_iter().fold(RepoBuilder::new(path).bare(true), |builder, command| builder.command(command));
+        builder.build().unwrap()
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let generated = build_fixture(&dir.path().join("original")).unwrap();
+
+        let commands = export_repository(generated.repository(), &ExportOptions::default()).unwrap();
+        assert_eq!(commands.len(), 4 + 2 + 1);
+
+        let copy = import(dir.path().join("copy"), commands);
+
+        // Same contents, identities and dates => same commit hashes
+        for name in &["refs/heads/master", "refs/heads/feature"] {
+            let original = generated.repository().refname_to_id(name).unwrap();
+            assert_eq!(copy.repository().refname_to_id(name).unwrap(), original);
+        }
+
+        let tag = copy.repository().revparse_single("1.0.0^{commit}").unwrap();
+        assert_eq!(tag.id(), generated.oid("d").unwrap());
+        let tag = copy.repository().revparse_single("1.0.0").unwrap();
+        assert_eq!(tag.as_tag().unwrap().message(), Some("Release 1.0.0\n"));
+    }
+
+    #[test]
+    fn export_options_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let generated = build_fixture(&dir.path().join("original")).unwrap();
+
+        let options = ExportOptions { drop_contents: true, anonymize: true, depth: Some(1) };
+        let commands = export_repository(generated.repository(), &options).unwrap();
+
+        // Only the tips: "d" (master) and "b" (feature)
+        let commit_ids: Vec<&str> = commands.iter().filter_map(|command| match command {
+            Command::Commit { id, .. } | Command::Merge { id, .. } => Some(id.as_str()),
+            _ => None
+        }).collect();
+        assert_eq!(commit_ids.len(), 2);
+
+        for command in &commands {
+            if let Command::Commit { tree, author, .. } | Command::Merge { tree, author, .. } = command {
+                let author = author.as_ref().unwrap();
+                assert!(author.name.as_ref().unwrap().starts_with("Person "));
+                assert!(matches!(tree.as_ref().unwrap().get("README"), Some(TreeNode::Utf8 { utf8, .. }) if utf8.is_empty()));
+            }
+        }
+
+        // "b" becomes a root commit, and "d" loses its parent "c"
+        assert!(matches!(&commands[0], Command::Commit { parents, .. } if parents.is_empty()));
+        assert!(matches!(&commands[1], Command::Commit { parents, .. } if parents.len() == 1));
+
+        import(dir.path().join("copy"), commands);
+    }
+
+    // Messages that can't be kept as they are would change the hashes
+    #[test]
+    fn message_encoding_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let generated = build_fixture(dir.path()).unwrap();
+        let repo = generated.repository();
+        let tree = repo.find_commit(generated.oid("a").unwrap()).unwrap().tree_id();
+
+        let headers = format!("tree {}\nauthor A <a@example.com> 0 +0000\ncommitter A <a@example.com> 0 +0000\n", tree);
+        // "café" in ISO-8859-1, and a message with an encoding header
+        let latin1 = [headers.as_bytes(), b"\ncaf\xe9\n"].concat();
+        let encoding = [headers.as_bytes(), b"encoding ISO-8859-1\n\nCafe\n"].concat();
+
+        for raw in &[latin1, encoding] {
+            let oid = repo.odb().unwrap().write(ObjectType::Commit, raw).unwrap();
+            repo.reference("refs/heads/encoded", oid, true, "test").unwrap();
+
+            let error = export_repository(repo, &ExportOptions::default()).unwrap_err();
+            assert!(matches!(error, Error::InvalidValue(_)), "{}", error);
+        }
+
+        repo.find_reference("refs/heads/encoded").unwrap().delete().unwrap();
+        assert!(export_repository(repo, &ExportOptions::default()).is_ok());
+    }
+}
@@ -0,0 +1,727 @@
+// Reads a `git fast-export` (or fast-import) stream, and converts it into a list of commands.
+//
+// Commits keep their messages, identities, dates and trees, so they get the same hashes as the
+// exported commits. Commit ids are the marks (e.g. ":3"), or the "# id" comment that precedes a
+// commit in the streams written by `--output fast-import`.
+//
+// Only the blobs and commits in the stream can be referenced: "from", "merge" and "M" take marks,
+// or refs that were written earlier in the stream.
+
+use base64::Engine;
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+
+use super::command::{Command, TreeNode, Identity, GitlinkTarget};
+use super::error::Error;
+use super::interpreter::{MODE_FILE, MODE_EXECUTABLE, MODE_SYMLINK, MODE_GITLINK};
+
+#[derive(Clone)]
+enum File {
+    Blob { mode: i32, contents: Rc<Vec<u8>> },
+    Gitlink(GitlinkTarget),
+}
+
+// Path => file. Directories are implied by the paths.
+type Files = BTreeMap<String, File>;
+
+struct Parser<'a> {
+    stream: &'a [u8],
+    position: usize,
+    // The line number of the last line that was read
+    line: usize,
+
+    // Mark => blob contents
+    blobs: HashMap<usize, Rc<Vec<u8>>>,
+    // Mark => commit id
+    commit_marks: HashMap<usize, String>,
+    // Commit id => the commit's files
+    trees: HashMap<String, Files>,
+    // Ref name => commit id
+    tips: BTreeMap<String, String>,
+
+    commands: Vec<Command>,
+}
+
+fn is_hash(s: &str) -> bool {
+    s.len() == 40 && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// "John <john@example.com> 1500000000 +0200" => identity with a date
+fn parse_identity(s: &str) -> Option<Identity> {
+    let open = s.find('<')?;
+    let close = open + s[open..].find('>')?;
+
+    Some(Identity {
+        name: Some(s[..open].trim_end().to_string()),
+        email: Some(s[open + 1..close].to_string()),
+        date: Some(s[close + 1..].trim().to_string()),
+    })
+}
+
+// Paths are unquoted, or C-style quoted with backslash escapes (e.g. "dir/new\nline").
+// Returns the path and the rest of the line. An unquoted path ends at a space if `to_space` is set.
+fn parse_path(s: &str, to_space: bool) -> Result<(String, &str), String> {
+    if !s.starts_with('"') {
+        let end = if to_space { s.find(' ').unwrap_or(s.len()) } else { s.len() };
+        return Ok((s[..end].to_string(), s[end..].trim_start()));
+    }
+
+    let bytes = s.as_bytes();
+    let mut path: Vec<u8> = Vec::new();
+    let mut i = 1;
+
+    loop {
+        match bytes.get(i) {
+            Some(b'"') => break,
+            Some(b'\\') => {
+                let escaped = match bytes.get(i + 1) {
+                    Some(b'n') => b'\n',
+                    Some(b't') => b'\t',
+                    Some(b'\\') => b'\\',
+                    Some(b'"') => b'"',
+                    Some(b'a') => 7,
+                    Some(b'b') => 8,
+                    Some(b'f') => 12,
+                    Some(b'r') => b'\r',
+                    Some(b'v') => 11,
+                    // Octal, e.g. "\303\251"
+                    Some(b'0'..=b'7') => {
+                        let digits = s.get(i + 1..i + 4).ok_or("Unterminated escape sequence")?;
+                        let byte = u8::from_str_radix(digits, 8).map_err(|_| format!("Invalid escape sequence: \\{}", digits))?;
+                        i += 2;
+                        byte
+                    },
+                    Some(other) => return Err(format!("Unknown escape sequence: \\{}", *other as char)),
+                    None => return Err("Unterminated path".to_string()),
+                };
+                path.push(escaped);
+                i += 2;
+            },
+            Some(other) => {
+                path.push(*other);
+                i += 1;
+            },
+            None => return Err("Unterminated path".to_string()),
+        }
+    }
+
+    let path = String::from_utf8(path).map_err(|_| "Paths must be UTF-8".to_string())?;
+    Ok((path, s[i + 1..].trim_start()))
+}
+
+fn tree_node(file: &File) -> TreeNode {
+    match file {
+        File::Gitlink(target) => TreeNode::Gitlink { gitlink: target.clone(), url: None },
+        File::Blob { mode, contents } if *mode == MODE_SYMLINK => {
+            TreeNode::Symlink { symlink: String::from_utf8_lossy(contents).into_owned() }
+        },
+        File::Blob { mode, contents } => {
+            // Only 100644 and 100755 get here, see parse_modify
+            let executable = *mode == MODE_EXECUTABLE;
+
+            match std::str::from_utf8(contents) {
+                Ok(utf8) if !executable => TreeNode::Utf8File(utf8.to_string()),
+                Ok(utf8) => TreeNode::Utf8 { utf8: utf8.to_string(), mode: None, executable },
+                Err(_) => TreeNode::Base64 {
+                    base64: base64::engine::general_purpose::STANDARD.encode(contents.as_slice()),
+                    mode: None,
+                    executable
+                }
+            }
+        }
+    }
+}
+
+// Removes a file, or a directory with everything in it
+fn remove_path(files: &mut Files, path: &str) -> Vec<(String, File)> {
+    let prefix = format!("{}/", path);
+    let paths: Vec<String> = files.keys()
+        .filter(|p| p.as_str() == path || p.starts_with(&prefix))
+        .cloned()
+        .collect();
+
+    paths.into_iter().map(|p| {
+        let file = files.remove(&p).unwrap();
+        (p, file)
+    }).collect()
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: String) -> Error {
+        Error::FastExport { line: self.line, message }
+    }
+
+    fn peek_line(&self) -> Option<&'a [u8]> {
+        if self.position >= self.stream.len() {
+            return None;
+        }
+
+        let rest = &self.stream[self.position..];
+        let end = rest.iter().position(|b| *b == b'\n').unwrap_or(rest.len());
+        Some(&rest[..end])
+    }
+
+    // Reads the next line as raw bytes
+    fn skip_line(&mut self) -> Option<&'a [u8]> {
+        let line = self.peek_line()?;
+        self.position += line.len() + 1;
+        self.line += 1;
+        Some(line)
+    }
+
+    // Paths and identities are kept as they are, so the lines must be UTF-8
+    fn next_line(&mut self) -> Result<Option<String>, Error> {
+        match self.skip_line() {
+            Some(line) => match std::str::from_utf8(line) {
+                Ok(line) => Ok(Some(line.to_string())),
+                Err(_) => Err(self.error("Paths and identities must be UTF-8".to_string()))
+            },
+            None => Ok(None)
+        }
+    }
+
+    // Reads the next line if it starts with the prefix, and returns the rest of it
+    fn next_line_with(&mut self, prefix: &str) -> Result<Option<String>, Error> {
+        match self.peek_line() {
+            Some(line) if line.starts_with(prefix.as_bytes()) => {
+                let line = self.next_line()?.unwrap();
+                Ok(Some(line[prefix.len()..].to_string()))
+            },
+            _ => Ok(None)
+        }
+    }
+
+    // "data <count>" followed by the raw bytes, or "data <<<delimiter>" followed by lines.
+    // The optional blank line after the data is skipped.
+    fn read_data(&mut self) -> Result<Vec<u8>, Error> {
+        let header = match self.next_line_with("data ")? {
+            Some(header) => header,
+            None => return Err(self.error("Expected `data`".to_string()))
+        };
+
+        let data = if let Some(delimiter) = header.strip_prefix("<<") {
+            let mut data = Vec::new();
+            loop {
+                // The raw bytes, so that messages aren't decoded lossily
+                match self.peek_line() {
+                    Some(line) if line == delimiter.as_bytes() => {
+                        self.skip_line();
+                        break;
+                    },
+                    Some(line) => {
+                        data.extend_from_slice(line);
+                        data.push(b'\n');
+                        self.skip_line();
+                    },
+                    None => return Err(self.error(format!("Missing the data delimiter: {}", delimiter)))
+                }
+            }
+            // The LF before the delimiter isn't part of the data
+            data.pop();
+            data
+        } else {
+            let count: usize = header.parse().map_err(|_| self.error(format!("Invalid data length: {}", header)))?;
+            if self.position + count > self.stream.len() {
+                return Err(self.error("The stream ends in the middle of data".to_string()));
+            }
+
+            let data = self.stream[self.position..self.position + count].to_vec();
+            self.position += count;
+            self.line += data.iter().filter(|b| **b == b'\n').count();
+            data
+        };
+
+        if self.peek_line() == Some(b"") {
+            self.skip_line();
+        }
+
+        Ok(data)
+    }
+
+    // Messages are kept as they are, so they must be UTF-8
+    fn read_message(&mut self) -> Result<String, Error> {
+        let data = self.read_data()?;
+        String::from_utf8(data).map_err(|_| self.error("Messages must be UTF-8".to_string()))
+    }
+
+    fn read_mark(&mut self) -> Result<Option<usize>, Error> {
+        match self.next_line_with("mark :")? {
+            Some(mark) => mark.parse().map(Some).map_err(|_| self.error(format!("Invalid mark: :{}", mark))),
+            None => Ok(None)
+        }
+    }
+
+    fn skip_original_oid(&mut self) {
+        if self.peek_line().is_some_and(|line| line.starts_with(b"original-oid ")) {
+            self.skip_line();
+        }
+    }
+
+    // A mark (":3") or a ref from earlier in the stream
+    fn resolve_commit(&self, commitish: &str) -> Result<String, Error> {
+        let commitish = commitish.trim_end_matches("^0");
+
+        let found = if let Some(mark) = commitish.strip_prefix(':') {
+            mark.parse().ok().and_then(|mark: usize| self.commit_marks.get(&mark))
+        } else {
+            self.tips.get(commitish).or_else(|| self.tips.get(&format!("refs/heads/{}", commitish)))
+        };
+
+        match found {
+            Some(id) => Ok(id.clone()),
+            None => Err(self.error(format!("Unknown commit (only marks and refs from earlier in the stream can be used): {}", commitish)))
+        }
+    }
+
+    // Some(None) for the null hash, which deletes a ref
+    fn read_from(&mut self) -> Result<Option<Option<String>>, Error> {
+        match self.next_line_with("from ")? {
+            Some(commitish) if commitish.len() == 40 && commitish.chars().all(|c| c == '0') => Ok(Some(None)),
+            Some(commitish) => self.resolve_commit(&commitish).map(|id| Some(Some(id))),
+            None => Ok(None)
+        }
+    }
+
+    fn parse_blob(&mut self) -> Result<(), Error> {
+        let mark = self.read_mark()?;
+        self.skip_original_oid();
+        let data = self.read_data()?;
+
+        if let Some(mark) = mark {
+            self.blobs.insert(mark, Rc::new(data));
+        }
+        Ok(())
+    }
+
+    // "M <mode> <dataref> <path>", where the dataref is a mark, "inline" or a commit hash (for gitlinks)
+    fn parse_modify(&mut self, args: &str, files: &mut Files) -> Result<(), Error> {
+        let mut parts = args.splitn(3, ' ');
+        let (mode, dataref, path) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(mode), Some(dataref), Some(path)) => (mode, dataref, path),
+            _ => return Err(self.error(format!("Invalid file change: M {}", args)))
+        };
+
+        let mode = i32::from_str_radix(mode, 8).map_err(|_| self.error(format!("Invalid file mode: {}", mode)))?;
+        // Short modes, e.g. "644"
+        let mode = if mode < 0o1000 { mode | 0o100000 } else { mode };
+        let (path, _) = parse_path(path, false).map_err(|message| self.error(message))?;
+        match mode {
+            MODE_FILE | MODE_EXECUTABLE | MODE_SYMLINK | MODE_GITLINK => (),
+            0o40000 => return Err(self.error(format!("Trees can't be referenced, only blobs: {}", path))),
+            _ => return Err(self.error(format!("Unsupported file mode (only 100644, 100755, 120000 and 160000 are supported): {:o}", mode)))
+        }
+
+        let file = if mode == MODE_GITLINK {
+            match dataref.strip_prefix(':') {
+                Some(_) => File::Gitlink(GitlinkTarget::Id { id: self.resolve_commit(dataref)? }),
+                None if is_hash(dataref) => File::Gitlink(GitlinkTarget::Hash(dataref.to_string())),
+                None => return Err(self.error(format!("Invalid gitlink: {}", dataref)))
+            }
+        } else {
+            let contents = if dataref == "inline" {
+                Rc::new(self.read_data()?)
+            } else {
+                let blob = dataref.strip_prefix(':').and_then(|mark| mark.parse().ok()).and_then(|mark: usize| self.blobs.get(&mark));
+                match blob {
+                    Some(blob) => blob.clone(),
+                    None => return Err(self.error(format!("Unknown blob (only marks from earlier in the stream can be used): {}", dataref)))
+                }
+            };
+            if mode == MODE_SYMLINK && std::str::from_utf8(&contents).is_err() {
+                return Err(self.error(format!("Symlink targets must be UTF-8: {}", path)));
+            }
+            File::Blob { mode, contents }
+        };
+
+        // A file replaces a directory with the same path
+        remove_path(files, &path);
+        files.insert(path, file);
+        Ok(())
+    }
+
+    // "C <source> <destination>" or "R <source> <destination>"
+    fn parse_copy(&mut self, args: &str, files: &mut Files, rename: bool) -> Result<(), Error> {
+        let (source, rest) = parse_path(args, true).map_err(|message| self.error(message))?;
+        let (destination, _) = parse_path(rest, false).map_err(|message| self.error(message))?;
+
+        let moved = if rename {
+            remove_path(files, &source)
+        } else {
+            let mut copy = files.clone();
+            remove_path(&mut copy, &source)
+        };
+        if moved.is_empty() {
+            return Err(self.error(format!("Path doesn't exist: {}", source)));
+        }
+
+        remove_path(files, &destination);
+        for (path, file) in moved {
+            files.insert(format!("{}{}", destination, &path[source.len()..]), file);
+        }
+        Ok(())
+    }
+
+    fn parse_commit(&mut self, reference: &str, comment: Option<String>) -> Result<(), Error> {
+        let mark = self.read_mark()?;
+        self.skip_original_oid();
+
+        let author = self.next_line_with("author ")?;
+        let committer = match self.next_line_with("committer ")? {
+            Some(committer) => committer,
+            None => return Err(self.error("Expected `committer`".to_string()))
+        };
+        let author = author.unwrap_or_else(|| committer.clone());
+        if let Some(encoding) = self.next_line_with("encoding ")? {
+            if !encoding.eq_ignore_ascii_case("utf-8") && !encoding.eq_ignore_ascii_case("utf8") {
+                return Err(self.error(format!("Unsupported encoding (only UTF-8 is supported): {}", encoding)));
+            }
+        }
+        let message = self.read_message()?;
+
+        // Without "from", a commit continues the branch
+        let mut parents: Vec<String> = Vec::new();
+        match self.read_from()? {
+            Some(Some(parent)) => parents.push(parent),
+            Some(None) => (),
+            None => parents.extend(self.tips.get(reference).cloned()),
+        }
+        while let Some(commitish) = self.next_line_with("merge ")? {
+            parents.push(self.resolve_commit(&commitish)?);
+        }
+
+        let mut files = match parents.first() {
+            Some(parent) => self.trees[parent].clone(),
+            None => Files::new()
+        };
+
+        while let Some(line) = self.peek_line() {
+            if line.starts_with(b"M ") {
+                let args = self.next_line()?.unwrap()[2..].to_string();
+                self.parse_modify(&args, &mut files)?;
+            } else if line.starts_with(b"D ") {
+                let args = self.next_line()?.unwrap()[2..].to_string();
+                let (path, _) = parse_path(&args, false).map_err(|message| self.error(message))?;
+                remove_path(&mut files, &path);
+            } else if line.starts_with(b"C ") || line.starts_with(b"R ") {
+                let line = self.next_line()?.unwrap();
+                self.parse_copy(&line[2..], &mut files, line.starts_with('R'))?;
+            } else if line == b"deleteall" {
+                self.skip_line();
+                files.clear();
+            } else if line.starts_with(b"N ") {
+                return Err(self.error("Notes aren't supported".to_string()));
+            } else {
+                break;
+            }
+        }
+
+        // The "# id" comment, unless that id is taken
+        let id = match (comment, mark) {
+            (Some(comment), _) if !self.trees.contains_key(&comment) => comment,
+            (_, Some(mark)) => format!(":{}", mark),
+            _ => format!("commit-{}", self.trees.len() + 1),
+        };
+        if self.trees.contains_key(&id) {
+            return Err(self.error(format!("Duplicate commit id: {}", id)));
+        }
+
+        let author = parse_identity(&author).ok_or_else(|| self.error(format!("Invalid identity: {}", author)))?;
+        let committer = parse_identity(&committer).ok_or_else(|| self.error(format!("Invalid identity: {}", committer)))?;
+        let tree: HashMap<String, TreeNode> = files.iter().map(|(path, file)| (path.clone(), tree_node(file))).collect();
+
+        let command = if parents.len() > 1 {
+            Command::Merge {
+                id: id.clone(),
+                commits: parents,
+                message: Some(message),
+                tree: Some(tree),
+                delete: Vec::new(),
+                incremental: Some(false),
+                author: Some(author),
+                committer: Some(committer),
+                author_date: None,
+                committer_date: None,
+                branches: None,
+                tags: None,
+                strategy: None,
+                resolutions: None,
+                no_ff: true,
+                in_progress: false,
+            }
+        } else {
+            Command::Commit {
+                id: id.clone(),
+                message: Some(message),
+                parents,
+                tree: Some(tree),
+                delete: Vec::new(),
+                incremental: Some(false),
+                author: Some(author),
+                committer: Some(committer),
+                author_date: None,
+                committer_date: None,
+                branches: None,
+                tags: None,
+            }
+        };
+        self.commands.push(command);
+
+        if let Some(mark) = mark {
+            self.commit_marks.insert(mark, id.clone());
+        }
+        self.trees.insert(id.clone(), files);
+        self.tips.insert(reference.to_string(), id);
+        Ok(())
+    }
+
+    fn parse_reset(&mut self, reference: &str) -> Result<(), Error> {
+        match self.read_from()? {
+            Some(Some(id)) => { self.tips.insert(reference.to_string(), id); },
+            _ => { self.tips.remove(reference); },
+        }
+        Ok(())
+    }
+
+    fn parse_tag(&mut self, name: &str) -> Result<(), Error> {
+        self.read_mark()?;
+        let on = match self.read_from()? {
+            Some(Some(id)) => id,
+            _ => return Err(self.error("Expected `from` with a commit".to_string()))
+        };
+        self.skip_original_oid();
+
+        let tagger = match self.next_line_with("tagger ")? {
+            Some(tagger) => Some(parse_identity(&tagger).ok_or_else(|| self.error(format!("Invalid identity: {}", tagger)))?),
+            None => None
+        };
+        let message = self.read_message()?;
+
+        self.commands.push(Command::Tag { name: name.to_string(), on, tagger, message: Some(message), lightweight: false });
+        Ok(())
+    }
+
+    fn parse(mut self) -> Result<Vec<Command>, Error> {
+        let mut comment: Option<String> = None;
+
+        while let Some(line) = self.next_line()? {
+            let (keyword, argument) = match line.find(' ') {
+                Some(space) => (&line[..space], &line[space + 1..]),
+                None => (line.as_str(), "")
+            };
+
+            match keyword {
+                "" => continue,
+                "#" => {
+                    comment = Some(argument.to_string());
+                    continue;
+                },
+                "blob" => self.parse_blob()?,
+                "commit" => self.parse_commit(argument, comment.take())?,
+                "reset" => self.parse_reset(argument)?,
+                "tag" => self.parse_tag(argument)?,
+                "feature" | "option" | "progress" | "checkpoint" => (),
+                "done" => break,
+                _ if keyword.starts_with('#') => (),
+                _ => return Err(self.error(format!("Unsupported command: {}", line)))
+            }
+            comment = None;
+        }
+
+        // Branches and lightweight tags point to the last commit of their ref. Other refs are skipped.
+        let tips = std::mem::take(&mut self.tips);
+        for (reference, on) in tips {
+            if let Some(name) = reference.strip_prefix("refs/heads/") {
+                self.commands.push(Command::Branch { name: name.to_string(), on });
+            } else if let Some(name) = reference.strip_prefix("refs/tags/") {
+                let annotated = self.commands.iter().any(|command| matches!(command, Command::Tag { name: tag, .. } if tag == name));
+                if !annotated {
+                    self.commands.push(Command::Tag { name: name.to_string(), on, tagger: None, message: None, lightweight: true });
+                }
+            }
+        }
+
+        Ok(self.commands)
+    }
+}
+
+pub fn parse_fast_export(stream: &[u8]) -> Result<Vec<Command>, Error> {
+    let parser = Parser {
+        stream,
+        position: 0,
+        line: 0,
+        blobs: HashMap::new(),
+        commit_marks: HashMap::new(),
+        trees: HashMap::new(),
+        tips: BTreeMap::new(),
+        commands: Vec::new(),
+    };
+
+    parser.parse()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::backend::FastImportBackend;
+    use super::super::dsl::parse_dsl;
+    use super::super::interpreter::Interpreter;
+    use super::super::init_repository;
+
+    // Written by `git fast-export --all --reference-excluded-parents` (with marks and an annotated tag)
+    const STREAM: &str = "blob
+mark :1
+data 6
+Hello
+
+reset refs/heads/master
+commit refs/heads/master
+mark :2
+author Someone <someone@example.com> 1500000000 +0200
+committer Someone <someone@example.com> 1500000000 +0200
+data 8
+Initial
+M 100644 :1 README
+M 100755 inline \"bin/run me\"
+data <<EOT
+#!/bin/sh
+EOT
+
+commit refs/heads/feature
+mark :3
+author Someone <someone@example.com> 1500000100 +0200
+committer Someone <someone@example.com> 1500000100 +0200
+data 8
+Feature
+from :2
+R README docs/README
+M 120000 inline link
+data 11
+docs/README
+
+commit refs/heads/master
+mark :4
+committer Other <other@example.com> 1500000200 +0000
+data 6
+Merge
+merge :3
+D bin
+
+tag 1.0.0
+from :4
+tagger Someone <someone@example.com> 1500000300 +0200
+data 8
+Release
+
+reset refs/tags/v1
+from :2
+
+";
+
+    #[test]
+    fn parse_fast_export_test() {
+        let commands = parse_fast_export(STREAM.as_bytes()).unwrap();
+        assert_eq!(commands.len(), 3 + 1 + 2 + 1);
+
+        match &commands[1] {
+            Command::Commit { id, parents, tree, .. } => {
+                assert_eq!(id, ":3");
+                assert_eq!(parents, &vec![":2".to_string()]);
+                let tree = tree.as_ref().unwrap();
+                assert!(matches!(tree.get("docs/README"), Some(TreeNode::Utf8File(s)) if s == "Hello\n"));
+                assert!(matches!(tree.get("bin/run me"), Some(TreeNode::Utf8 { utf8, executable: true, .. }) if utf8 == "#!/bin/sh"));
+                assert!(matches!(tree.get("link"), Some(TreeNode::Symlink { symlink }) if symlink == "docs/README"));
+                assert!(tree.get("README").is_none());
+            },
+            other => panic!("Expected a commit: {:?}", other)
+        }
+
+        // Without "from", the commit continues master. The author defaults to the committer.
+        match &commands[2] {
+            Command::Merge { commits, tree, author, .. } => {
+                assert_eq!(commits, &vec![":2".to_string(), ":3".to_string()]);
+                assert_eq!(tree.as_ref().unwrap().len(), 1);
+                assert_eq!(author.as_ref().unwrap().name.as_deref(), Some("Other"));
+            },
+            other => panic!("Expected a merge: {:?}", other)
+        }
+
+        assert!(matches!(&commands[3], Command::Tag { name, on, message: Some(message), lightweight: false, .. } if name == "1.0.0" && on == ":4" && message == "Release\n"));
+        assert!(matches!(&commands[6], Command::Tag { name, on, lightweight: true, .. } if name == "v1" && on == ":2"));
+
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init_repository(dir.path(), true).unwrap();
+        Interpreter::new(&repo).unwrap().interpret_commands(&commands).unwrap();
+    }
+
+    #[test]
+    fn parse_path_test() {
+        assert_eq!(parse_path("dir/file name", false).unwrap(), ("dir/file name".to_string(), ""));
+        assert_eq!(parse_path("source destination", true).unwrap(), ("source".to_string(), "destination"));
+        assert_eq!(parse_path(r#""new\nline" rest"#, true).unwrap(), ("new\nline".to_string(), "rest"));
+        assert_eq!(parse_path(r#""caf\303\251""#, false).unwrap(), ("café".to_string(), ""));
+        assert!(parse_path(r#""unterminated"#, false).is_err());
+        // "caf\351" is "café" in ISO-8859-1
+        assert!(parse_path(r#""caf\351""#, false).is_err());
+    }
+
+    #[test]
+    fn parse_error_test() {
+        let error = parse_fast_export(b"commit refs/heads/master\ncommitter A <a@example.com> 0 +0000\ndata 1\nx\nfrom :7\n").unwrap_err();
+        assert!(matches!(error, Error::FastExport { line: 5, .. }), "{}", error);
+
+        assert!(matches!(parse_fast_export(b"ls :1 README\n"), Err(Error::FastExport { line: 1, .. })));
+
+        let error = parse_fast_export(b"commit refs/heads/master\ncommitter A <a@example.com> 0 +0000\ndata 1\nx\nM 100664 inline README\ndata 0\n").unwrap_err();
+        assert!(matches!(error, Error::FastExport { line: 5, .. }), "{}", error);
+
+        let error = parse_fast_export(b"commit refs/heads/master\ncommitter A <a@example.com> 0 +0000\nencoding ISO-8859-1\ndata 1\nx\n").unwrap_err();
+        assert!(matches!(error, Error::FastExport { line: 3, .. }), "{}", error);
+
+        let error = parse_fast_export(b"commit refs/heads/master\ncommitter A <a@example.com> 0 +0000\ndata 1\n\xe9\n").unwrap_err();
+        assert!(error.to_string().contains("UTF-8"), "{}", error);
+        assert!(parse_fast_export(b"commit refs/heads/master\ncommitter A <a@example.com> 0 +0000\nencoding UTF-8\ndata 1\nx\n").is_ok());
+
+        // Paths and identities that aren't UTF-8 would change the hashes
+        let error = parse_fast_export(b"commit refs/heads/master\ncommitter A <a@example.com> 0 +0000\ndata 1\nx\nM 100644 inline caf\xe9\ndata 0\n").unwrap_err();
+        assert!(matches!(error, Error::FastExport { line: 5, .. }), "{}", error);
+        let error = parse_fast_export(b"commit refs/heads/master\ncommitter Andr\xe9 <a@example.com> 0 +0000\ndata 1\nx\n").unwrap_err();
+        assert!(matches!(error, Error::FastExport { line: 2, .. }), "{}", error);
+
+        // Binary contents are fine, but not as symlink targets
+        assert!(parse_fast_export(b"commit refs/heads/master\ncommitter A <a@example.com> 0 +0000\ndata 1\nx\nM 100644 inline data.bin\ndata <<EOF\n\xe9\nEOF\n").is_ok());
+        assert!(parse_fast_export(b"commit refs/heads/master\ncommitter A <a@example.com> 0 +0000\ndata 1\nx\nM 120000 inline link\ndata 1\n\xe9\n").is_err());
+    }
+
+    // The stream written by `--output fast-import` reads back into the same commits, with the same ids
+    #[test]
+    fn round_trip_test() {
+        let commands = parse_dsl(r#"
+config date "1500000000 +0200"
+a: "Initial"
+  README: "Hello"
+b < a: "Commit B" [branch feature]
+  README: "Hello B"
+c < a: "Commit C"
+  c.txt: "C"
+d = merge b c: "Merge" [tag 1.0.0]
+  README: "Merged"
+branch master -> d
+"#).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let original = init_repository(dir.path().join("original"), true).unwrap();
+        let mut interpreter = Interpreter::with_backend(&original, FastImportBackend::new(Vec::new())).unwrap();
+        interpreter.interpret_commands(&commands).unwrap();
+        let original_ids = interpreter.id_to_oid_lookup().clone();
+        let stream = interpreter.finish().unwrap().into_inner();
+
+        let copy = init_repository(dir.path().join("copy"), true).unwrap();
+        let mut interpreter = Interpreter::new(&copy).unwrap();
+        interpreter.interpret_commands(&parse_fast_export(&stream).unwrap()).unwrap();
+
+        assert_eq!(interpreter.id_to_oid_lookup(), &original_ids);
+        assert_eq!(copy.refname_to_id("refs/heads/master").unwrap(), original_ids["d"]);
+        assert_eq!(copy.refname_to_id("refs/heads/feature").unwrap(), original_ids["b"]);
+        assert!(copy.find_reference("refs/generate-git-repo/scratch").is_err());
+    }
+}
@@ -0,0 +1,152 @@
+use serde::Deserialize;
+use std::path::Path;
+use std::str::FromStr;
+
+use super::command::Command;
+use super::dsl::parse_dsl;
+use super::error::Error;
+use super::fast_export::parse_fast_export;
+
+// The input formats that deserialize into a list of commands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Yaml,
+    Toml,
+    Dsl,
+    // A `git fast-export` stream
+    FastExport,
+}
+
+// TOML documents can't be a top-level array, so the commands are in an array of tables:
+//
+//     [[commands]]
+//     type = "commit"
+//     id = "a"
+#[derive(Deserialize)]
+struct TomlCommands {
+    commands: Vec<Command>,
+}
+
+impl Format {
+    // Guesses the format from the file extension. Returns None for unknown extensions.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Format> {
+        let extension = path.as_ref().extension()?.to_str()?;
+        extension.parse().ok()
+    }
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Format, Error> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(Format::Json),
+            "yaml" | "yml" => Ok(Format::Yaml),
+            "toml" => Ok(Format::Toml),
+            "dsl" => Ok(Format::Dsl),
+            "fast-export" | "fi" => Ok(Format::FastExport),
+            _ => Err(Error::InvalidValue(format!("Unknown format (expected json, yaml, toml, dsl or fast-export): {}", s)))
+        }
+    }
+}
+
+// Syntax errors include the line and column
+pub fn parse_commands(contents: &str, format: Format) -> Result<Vec<Command>, Error> {
+    match format {
+        Format::Json => Ok(serde_json::from_str(contents)?),
+        Format::Yaml => serde_norway::from_str(contents).map_err(Error::Yaml),
+        Format::Toml => {
+            let toml_commands: TomlCommands = toml::from_str(contents).map_err(Error::Toml)?;
+            Ok(toml_commands.commands)
+        },
+        Format::Dsl => parse_dsl(contents),
+        // Binary contents need parse_fast_export, which takes bytes
+        Format::FastExport => parse_fast_export(contents.as_bytes()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::command::TreeNode;
+
+    fn commit_ids(commands: &[Command]) -> Vec<&str> {
+        commands.iter().map(|command| match command {
+            Command::Commit { id, .. } => id.as_str(),
+            other => panic!("Expected a commit: {:?}", other)
+        }).collect()
+    }
+
+    #[test]
+    fn from_path_test() {
+        assert_eq!(Format::from_path("input.json"), Some(Format::Json));
+        assert_eq!(Format::from_path("dir/input.YAML"), Some(Format::Yaml));
+        assert_eq!(Format::from_path("input.yml"), Some(Format::Yaml));
+        assert_eq!(Format::from_path("input.toml"), Some(Format::Toml));
+        assert_eq!(Format::from_path("input.dsl"), Some(Format::Dsl));
+        assert_eq!(Format::from_path("input.fi"), Some(Format::FastExport));
+        assert_eq!(Format::from_path("input.txt"), None);
+        assert_eq!(Format::from_path("input"), None);
+    }
+
+    #[test]
+    fn parse_yaml_test() {
+        let yaml = "
+- type: commit
+  id: a
+  message: |
+    Initial commit
+
+    With a body
+  tree:
+    README: |
+      Hello
+    bin/run: { utf8: '#!/bin/sh', executable: true }
+- type: commit
+  id: b
+  parents: [a]
+";
+        let commands = parse_commands(yaml, Format::Yaml).unwrap();
+        assert_eq!(commit_ids(&commands), vec!["a", "b"]);
+
+        match &commands[0] {
+            Command::Commit { message, tree, .. } => {
+                assert_eq!(message.as_deref(), Some("Initial commit\n\nWith a body\n"));
+                assert!(matches!(tree.as_ref().unwrap().get("README"), Some(TreeNode::Utf8File(s)) if s == "Hello\n"));
+                assert!(matches!(tree.as_ref().unwrap().get("bin/run"), Some(TreeNode::Utf8 { executable: true, .. })));
+            },
+            other => panic!("Expected a commit: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn parse_toml_test() {
+        let toml = r##"
+[[commands]]
+type = "commit"
+id = "a"
+message = """
+Initial commit
+"""
+
+[commands.tree]
+"README" = "Hello"
+"run.sh" = { utf8 = "#!/bin/sh", mode = 100755 }
+
+[[commands]]
+type = "commit"
+id = "b"
+parents = ["a"]
+"##;
+        let commands = parse_commands(toml, Format::Toml).unwrap();
+        assert_eq!(commit_ids(&commands), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn parse_error_test() {
+        assert!(matches!(parse_commands("[{", Format::Json), Err(Error::Json(_))));
+        assert!(matches!(parse_commands("- type: nope", Format::Yaml), Err(Error::Yaml(_))));
+        assert!(matches!(parse_commands("commands = 1", Format::Toml), Err(Error::Toml(_))));
+    }
+}
@@ -0,0 +1,326 @@
+// Renders the generated commits as a graph, labeled with their ids, branches and tags.
+//
+// e.g. ascii:
+//
+//     * d (master, tag: 1.0.0)
+//     |\
+//     | * c
+//     * | b
+//     |/
+//     * a
+
+use git2::{Repository, Oid, ObjectType};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::str::FromStr;
+
+use super::error::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphFormat {
+    Dot,
+    Ascii,
+    Mermaid,
+}
+
+impl FromStr for GraphFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<GraphFormat, Error> {
+        match s {
+            "dot" => Ok(GraphFormat::Dot),
+            "ascii" => Ok(GraphFormat::Ascii),
+            "mermaid" => Ok(GraphFormat::Mermaid),
+            _ => Err(Error::InvalidValue(format!("Unknown graph format (expected dot, ascii or mermaid): {}", s)))
+        }
+    }
+}
+
+struct Node {
+    // Fast-forward merges share a commit with another id
+    ids: Vec<String>,
+    // e.g. "master", "tag: 1.0.0"
+    refs: Vec<String>,
+    parents: Vec<Oid>,
+}
+
+impl Node {
+    fn label(&self, separator: &str) -> String {
+        let mut label = self.ids.join(", ");
+        for name in &self.refs {
+            label.push_str(separator);
+            label.push_str(name);
+        }
+        label
+    }
+}
+
+struct Graph {
+    nodes: HashMap<Oid, Node>,
+    // Children before their parents, newest first
+    order: Vec<Oid>,
+}
+
+fn build_graph(repo: &Repository, id_to_oid_lookup: &HashMap<String, Oid>) -> Result<Graph, Error> {
+    let mut nodes: HashMap<Oid, Node> = HashMap::new();
+    let generated: HashSet<Oid> = id_to_oid_lookup.values().cloned().collect();
+
+    for (id, oid) in id_to_oid_lookup {
+        if !nodes.contains_key(oid) {
+            // Only the parents that were generated are shown
+            let parents = repo.find_commit(*oid)?.parent_ids()
+                .filter(|parent| generated.contains(parent))
+                .collect();
+            nodes.insert(*oid, Node { ids: Vec::new(), refs: Vec::new(), parents });
+        }
+        nodes.get_mut(oid).unwrap().ids.push(id.clone());
+    }
+
+    // Sorted by name, so that the output is stable
+    let mut refs: BTreeMap<String, Oid> = BTreeMap::new();
+    for reference in repo.references()? {
+        let reference = reference?;
+        let label = match reference.name() {
+            Some(name) if name.starts_with("refs/heads/") => name["refs/heads/".len()..].to_string(),
+            Some(name) if name.starts_with("refs/tags/") => format!("tag: {}", &name["refs/tags/".len()..]),
+            _ => continue
+        };
+        if let Ok(commit) = reference.peel(ObjectType::Commit) {
+            refs.insert(label, commit.id());
+        }
+    }
+    for (label, oid) in refs {
+        if let Some(node) = nodes.get_mut(&oid) {
+            node.refs.push(label);
+        }
+    }
+
+    for node in nodes.values_mut() {
+        node.ids.sort();
+    }
+
+    let order = topological_order(repo, &nodes)?;
+
+    Ok(Graph { nodes, order })
+}
+
+// A commit is ready once all of its children are. Ties are broken by the newest commit date, then the id.
+fn topological_order(repo: &Repository, nodes: &HashMap<Oid, Node>) -> Result<Vec<Oid>, Error> {
+    let mut child_counts: HashMap<Oid, usize> = nodes.keys().map(|oid| (*oid, 0)).collect();
+    for node in nodes.values() {
+        for parent in &node.parents {
+            *child_counts.get_mut(parent).unwrap() += 1;
+        }
+    }
+
+    let mut sort_keys: HashMap<Oid, (i64, String)> = HashMap::new();
+    for (oid, node) in nodes {
+        sort_keys.insert(*oid, (repo.find_commit(*oid)?.time().seconds(), node.ids[0].clone()));
+    }
+
+    let mut ready: BTreeSet<(i64, String, Oid)> = BTreeSet::new();
+    for (oid, count) in &child_counts {
+        if *count == 0 {
+            let (time, id) = sort_keys[oid].clone();
+            ready.insert((time, id, *oid));
+        }
+    }
+
+    let mut order = Vec::new();
+    while let Some(next) = ready.iter().next_back().cloned() {
+        ready.remove(&next);
+        let oid = next.2;
+        order.push(oid);
+
+        for parent in &nodes[&oid].parents {
+            let count = child_counts.get_mut(parent).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                let (time, id) = sort_keys[parent].clone();
+                ready.insert((time, id, *parent));
+            }
+        }
+    }
+
+    Ok(order)
+}
+
+fn render_dot(graph: &Graph) -> String {
+    let mut dot = String::from("digraph commits {\n  rankdir=\"BT\";\n  node [shape=box];\n");
+
+    for oid in &graph.order {
+        let node = &graph.nodes[oid];
+        dot.push_str(&format!("  {:?} [label={:?}];\n", node.ids[0], node.label("\n")));
+    }
+    for oid in &graph.order {
+        let node = &graph.nodes[oid];
+        for parent in &node.parents {
+            dot.push_str(&format!("  {:?} -> {:?};\n", node.ids[0], graph.nodes[parent].ids[0]));
+        }
+    }
+
+    dot.push_str("}\n");
+    dot
+}
+
+fn render_mermaid(graph: &Graph) -> String {
+    // Ids can contain any character, so the Mermaid node names are numbered instead
+    let names: HashMap<Oid, String> = graph.order.iter().enumerate()
+        .map(|(index, oid)| (*oid, format!("c{}", index)))
+        .collect();
+
+    let mut mermaid = String::from("graph BT\n");
+
+    for oid in &graph.order {
+        let label = graph.nodes[oid].label("<br/>").replace('"', "#quot;");
+        mermaid.push_str(&format!("  {}[\"{}\"]\n", names[oid], label));
+    }
+    for oid in &graph.order {
+        for parent in &graph.nodes[oid].parents {
+            mermaid.push_str(&format!("  {} --> {}\n", names[oid], names[parent]));
+        }
+    }
+
+    mermaid
+}
+
+// A line of "|", "/" and "\" characters. Lane i is drawn at position 2 * i, and diagonals in between.
+fn draw(characters: &[(usize, char)]) -> String {
+    let width = characters.iter().map(|(position, _)| position + 1).max().unwrap_or(0);
+    let mut line = vec![' '; width];
+    for (position, c) in characters {
+        line[*position] = *c;
+    }
+    line.into_iter().collect()
+}
+
+// Each column ("lane") is waiting for a commit. A commit takes over the first lane that waits for it,
+// and the lane then waits for its first parent. Merges open new lanes for their other parents.
+fn render_ascii(graph: &Graph) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    let mut lanes: Vec<Oid> = Vec::new();
+
+    for oid in &graph.order {
+        let node = &graph.nodes[oid];
+
+        let mut waiting: Vec<usize> = (0..lanes.len()).filter(|i| lanes[*i] == *oid).collect();
+        if waiting.is_empty() {
+            lanes.push(*oid);
+            waiting.push(lanes.len() - 1);
+        }
+        let column = waiting[0];
+
+        // The other lanes that wait for this commit join the next waiting lane on their left, e.g. "|/".
+        // Lanes that are further away cross the lanes in between, e.g. "|_|/"
+        for pair in waiting.windows(2).rev() {
+            let (target, joined) = (pair[0], pair[1]);
+            let mut characters: Vec<(usize, char)> = (0..lanes.len())
+                .map(|i| if i < joined { (2 * i, '|') } else { (2 * i - 1, '/') })
+                .collect();
+            characters.extend((target..joined - 1).map(|i| (2 * i + 1, '_')));
+            lines.push(draw(&characters));
+            lanes.remove(joined);
+        }
+
+        // e.g. "| * | d (master)"
+        let characters: Vec<(usize, char)> = (0..lanes.len())
+            .map(|i| (2 * i, if i == column { '*' } else { '|' }))
+            .collect();
+        let mut line = draw(&characters);
+        line.push(' ');
+        line.push_str(&node.ids.join(", "));
+        if !node.refs.is_empty() {
+            line.push_str(&format!(" ({})", node.refs.join(", ")));
+        }
+        lines.push(line);
+
+        match node.parents.split_first() {
+            None => {
+                // The lanes on the right move over, e.g. "| /"
+                if column + 1 < lanes.len() {
+                    let characters: Vec<(usize, char)> = (0..lanes.len())
+                        .filter(|i| *i != column)
+                        .map(|i| if i < column { (2 * i, '|') } else { (2 * i - 1, '/') })
+                        .collect();
+                    lines.push(draw(&characters));
+                }
+                lanes.remove(column);
+            },
+            Some((first, others)) => {
+                lanes[column] = *first;
+
+                // Each other parent opens a lane next to the commit, and the lanes on the right move over, e.g. "|\"
+                for (index, parent) in others.iter().enumerate() {
+                    let opened = column + index + 1;
+                    let mut characters: Vec<(usize, char)> = (0..lanes.len())
+                        .map(|i| if i < opened { (2 * i, '|') } else { (2 * i + 1, '\\') })
+                        .collect();
+                    characters.push((2 * opened - 1, '\\'));
+                    lines.push(draw(&characters));
+                    lanes.insert(opened, *parent);
+                }
+            }
+        }
+    }
+
+    let mut ascii = lines.join("\n");
+    ascii.push('\n');
+    ascii
+}
+
+pub fn render_graph(repo: &Repository, id_to_oid_lookup: &HashMap<String, Oid>, format: GraphFormat) -> Result<String, Error> {
+    let graph = build_graph(repo, id_to_oid_lookup)?;
+
+    Ok(match format {
+        GraphFormat::Dot => render_dot(&graph),
+        GraphFormat::Ascii => render_ascii(&graph),
+        GraphFormat::Mermaid => render_mermaid(&graph),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::builder::{RepoBuilder, GeneratedRepo};
+    use super::super::dsl::parse_dsl;
+
+    fn generate(path: &std::path::Path, source: &str) -> GeneratedRepo {
+        parse_dsl(source).unwrap().into_iter()
+            .fold(RepoBuilder::new(path).bare(true).date("1500000000 +0000"), |builder, command| builder.command(command))
+            .build().unwrap()
+    }
+
+    const DIAMOND: &str = "a\nb < a\nc < a\nd = merge b c [tag 1.0.0]\nbranch master -> d\nbranch dev -> a\n";
+
+    #[test]
+    fn ascii_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let generated = generate(dir.path(), DIAMOND);
+
+        let ascii = render_graph(generated.repository(), generated.id_to_oid_lookup(), GraphFormat::Ascii).unwrap();
+        assert_eq!(ascii, "* d (master, tag: 1.0.0)\n|\\\n| * c\n* | b\n|/\n* a (dev)\n");
+    }
+
+    #[test]
+    fn ascii_roots_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let generated = generate(dir.path(), "a\nx\nb < a\nc = merge b x\ny < x\n");
+
+        let ascii = render_graph(generated.repository(), generated.id_to_oid_lookup(), GraphFormat::Ascii).unwrap();
+        assert_eq!(ascii, "* y\n| * c\n| |\\\n| * | b\n|_|/\n* | x\n /\n* a\n");
+    }
+
+    #[test]
+    fn dot_and_mermaid_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let generated = generate(dir.path(), DIAMOND);
+
+        let dot = render_graph(generated.repository(), generated.id_to_oid_lookup(), GraphFormat::Dot).unwrap();
+        assert!(dot.starts_with("digraph commits {\n"));
+        assert!(dot.contains("  \"d\" [label=\"d\\nmaster\\ntag: 1.0.0\"];\n"));
+        assert!(dot.contains("  \"d\" -> \"b\";\n  \"d\" -> \"c\";\n"));
+
+        let mermaid = render_graph(generated.repository(), generated.id_to_oid_lookup(), GraphFormat::Mermaid).unwrap();
+        assert!(mermaid.starts_with("graph BT\n  c0[\"d<br/>master<br/>tag: 1.0.0\"]\n"));
+        assert_eq!(mermaid.matches("-->").count(), 4);
+    }
+}
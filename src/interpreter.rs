@@ -1,27 +1,47 @@
-use git2::{Repository, Signature, Error, Oid, Commit, Tree};
+use git2::{Repository, Signature, Oid, Commit, Tree, Time, ObjectType, Index, IndexEntry, ErrorCode};
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
 
 use super::command::Command;
 use super::command::TreeNode;
+use super::command::FileMode;
+use super::command::GitlinkTarget;
+use super::command::MergeStrategy;
+use super::command::Identity;
+use super::command::OperationState;
 use super::can_fastforward::can_fastforward;
-
-const DEFAULT_NAME: &'static str  = "generate-git-repo";
-const DEFAULT_EMAIL: &'static str = "generate-git-repo@example.org";
-
-fn print_warning(message: &str) {
-    use colored::*;
-
-    let message = format!("WARNING: {}", message);
-    println!("{}", message.color("yellow"));
-}
+use super::date::{parse_date, Clock};
+use super::error::Error;
+use super::backend::{Backend, GitBackend};
+use super::worktree::apply_worktree;
+use super::state::{start_merge, start_rebase, start_pick, start_bisect, revert_message, Rebase};
+
+const DEFAULT_NAME: &str  = "generate-git-repo";
+const DEFAULT_EMAIL: &str = "generate-git-repo@example.org";
+const DEFAULT_DATE_INCREMENT: i64 = 60;
+
+// File permissions: rw-r--r--
+pub const MODE_FILE: i32       = 0o100644;
+// File permissions: rwxr-xr-x
+pub const MODE_EXECUTABLE: i32 = 0o100755;
+// Symbolic link. The blob contains the target path.
+pub const MODE_SYMLINK: i32    = 0o120000;
+// Submodule commit
+pub const MODE_GITLINK: i32    = 0o160000;
+
+const GITMODULES_PATH: &str = ".gitmodules";
 
 #[derive(Debug)]
 enum FileToWrite {
     File {
-        contents: Vec<u8>
+        contents: Vec<u8>,
+        mode: i32
     },
+    Gitlink(Oid),
+    // Only has an effect in incremental mode, where the path may already exist
+    Deleted,
     Directory(HashMap<String, FileToWrite>)
 }
 
@@ -29,21 +49,29 @@ fn split_path(path: &str) -> Vec<&str> {
     path.split("/").collect()
 }
 
+// Paths are relative to the top of the tree (or the work tree), and can't leave it or point into .git
+pub fn check_path(path: &str) -> Result<(), Error> {
+    let valid = split_path(path).iter()
+        .all(|part| !part.is_empty() && *part != "." && *part != ".." && !part.eq_ignore_ascii_case(".git"));
+
+    if valid { Ok(()) } else { Err(Error::InvalidPath(path.to_string())) }
+}
+
 fn add_to_files_to_write(files_to_write: &mut HashMap<String, FileToWrite>,
                          full_path: &str,
                          path_parts: &[&str],
-                         contents: Vec<u8>) {
+                         file: FileToWrite) -> Result<(), Error> {
     
     if path_parts.len() == 1 {
         let name = path_parts[0];
 
         if files_to_write.contains_key(name) {
-            // TODO: should be an error
-            panic!("File or directory already exists: {}", full_path);
+            return Err(Error::DuplicatePath(full_path.to_string()));
         }
 
         // just write the file
-        files_to_write.insert(name.to_string(), FileToWrite::File { contents: contents });
+        files_to_write.insert(name.to_string(), file);
+        Ok(())
     } else if path_parts.len() > 1 {
         let name = path_parts[0];
         let tail = &path_parts[1..];
@@ -56,36 +84,62 @@ fn add_to_files_to_write(files_to_write: &mut HashMap<String, FileToWrite>,
 
         if let Some(directory) = files_to_write.get_mut(name) {
             if let FileToWrite::Directory(hm) = directory {
-                add_to_files_to_write(hm, full_path, tail, contents);
+                add_to_files_to_write(hm, full_path, tail, file)
             } else {
-                // TODO: should be an error
-                // TODO: show full directory name
-                panic!("Already added as a non-directory: {}", name);
+                // e.g. "a/b/c" is being added, but "a/b" is a file
+                let full_parts = split_path(full_path);
+                let directory_path = full_parts[..full_parts.len() - tail.len()].join("/");
+                Err(Error::FileDirectoryConflict(directory_path))
             }
         } else {
             unreachable!();
         }
     } else {
-        panic!("Unexpected")
+        // split_path() always returns at least one part
+        unreachable!()
     }
 }
 
-fn create_tree_recur(repo: &Repository, tree: &HashMap<String, FileToWrite>) -> Result<Oid, Error> {
+fn create_tree_recur(repo: &Repository, base: Option<&Tree>, tree: &HashMap<String, FileToWrite>) -> Result<Oid, Error> {
     // Git trees are recursive, so it's easy to use a recursive function to make them.
+    // If there's a base tree, its entries are kept unless they're replaced or deleted.
 
-    let mut tree_builder = repo.treebuilder(None)?;
+    let mut tree_builder = repo.treebuilder(base)?;
 
     for (name, node) in tree.iter() {
         match node {
-            FileToWrite::File { contents } => {
+            FileToWrite::File { contents, mode } => {
                 let blob_oid = repo.blob(contents)?;
-                // File permissions: rw-r--r--
-                tree_builder.insert(name, blob_oid, 0o100644)?;
+                tree_builder.insert(name, blob_oid, *mode)?;
+            },
+            FileToWrite::Gitlink(commit_oid) => {
+                // The commit doesn't need to exist in this repository
+                tree_builder.insert(name, *commit_oid, MODE_GITLINK)?;
+            },
+            FileToWrite::Deleted => {
+                // Deleting a path that doesn't exist is a no-op
+                if tree_builder.get(name)?.is_some() {
+                    tree_builder.remove(name)?;
+                }
             },
             FileToWrite::Directory(subtree) => {
-                let subtree_oid = create_tree_recur(repo, subtree)?;
-                // File permissions: directory flag
-                tree_builder.insert(name, subtree_oid, 0o040000)?;
+                // If the directory already exists in the base tree, patch it
+                let base_subtree = match tree_builder.get(name)? {
+                    Some(ref entry) if entry.kind() == Some(ObjectType::Tree) => Some(repo.find_tree(entry.id())?),
+                    _ => None
+                };
+
+                let subtree_oid = create_tree_recur(repo, base_subtree.as_ref(), subtree)?;
+
+                if repo.find_tree(subtree_oid)?.is_empty() {
+                    // Git doesn't store empty directories (e.g. if all of its files were deleted)
+                    if tree_builder.get(name)?.is_some() {
+                        tree_builder.remove(name)?;
+                    }
+                } else {
+                    // File permissions: directory flag
+                    tree_builder.insert(name, subtree_oid, 0o040000)?;
+                }
             },
         }
     }
@@ -94,29 +148,295 @@ fn create_tree_recur(repo: &Repository, tree: &HashMap<String, FileToWrite>) ->
     Ok(tree_oid)
 }
 
-fn create_files_to_write(tree: &HashMap<String, TreeNode>) -> HashMap<String, FileToWrite> {
+fn decode_base64(path: &str, contents: &str) -> Result<Vec<u8>, Error> {
+    use base64::Engine;
+
+    base64::engine::general_purpose::STANDARD.decode(contents).map_err(|e| {
+        Error::InvalidValue(format!("Invalid base64 contents for {}: {}", path, e))
+    })
+}
+
+fn decode_hex(path: &str, contents: &str) -> Result<Vec<u8>, Error> {
+    hex::decode(contents).map_err(|e| {
+        Error::InvalidValue(format!("Invalid hex contents for {}: {}", path, e))
+    })
+}
+
+fn file_mode(path: &str, mode: &Option<FileMode>, executable: bool) -> Result<i32, Error> {
+    let mode = match mode {
+        Some(FileMode::Number(mode)) => mode.to_string(),
+        Some(FileMode::String(mode)) => mode.clone(),
+        None => return Ok(if executable { MODE_EXECUTABLE } else { MODE_FILE })
+    };
+
+    // Only regular and executable files can be expressed with a mode
+    match i32::from_str_radix(&mode, 8) {
+        Ok(MODE_FILE) if executable => Err(Error::InvalidValue(format!("The mode of {} is {}, but `executable` is set", path, mode))),
+        Ok(MODE_FILE) => Ok(MODE_FILE),
+        Ok(MODE_EXECUTABLE) => Ok(MODE_EXECUTABLE),
+        _ => Err(Error::InvalidValue(format!("Invalid mode for {} (expected 100644 or 100755): {}", path, mode)))
+    }
+}
+
+fn gitlink_oid(target: &GitlinkTarget, id_to_oid_lookup: &HashMap<String, Oid>) -> Result<Oid, Error> {
+    match target {
+        GitlinkTarget::Hash(hash) => Oid::from_str(hash).map_err(|_| {
+            Error::InvalidValue(format!("Invalid commit hash for gitlink: {}", hash))
+        }),
+        GitlinkTarget::Id { id } => match id_to_oid_lookup.get(id) {
+            Some(oid) => Ok(*oid),
+            None => Err(Error::UnknownId(id.to_string()))
+        }
+    }
+}
+
+// Submodules are listed in the same format that `git submodule add` uses
+fn create_gitmodules(submodules: &[(&str, &str)]) -> String {
+    let mut gitmodules = String::new();
+
+    for (path, url) in submodules {
+        gitmodules.push_str(&format!("[submodule \"{}\"]\n\tpath = {}\n\turl = {}\n", path, path, url));
+    }
+
+    gitmodules
+}
+
+// Adds the submodules to an existing .gitmodules. The sections of the same submodules are replaced,
+// and the other sections are kept in order.
+fn merge_gitmodules(base: &str, submodules: &[(&str, &str)]) -> String {
+    let mut merged = String::new();
+    let mut replaced = false;
+
+    for line in base.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            replaced = submodules.iter().any(|(path, _)| trimmed == format!("[submodule \"{}\"]", path));
+        }
+        if !replaced {
+            merged.push_str(line);
+            merged.push('\n');
+        }
+    }
+
+    merged + &create_gitmodules(submodules)
+}
+
+// The contents and mode of a file node, or None for a deleted path. Gitlinks aren't files.
+pub fn blob_contents(path: &str, node: &TreeNode) -> Result<Option<(Vec<u8>, i32)>, Error> {
+    let blob = match node {
+        TreeNode::Utf8File(contents) => (contents.as_bytes().to_vec(), MODE_FILE),
+        TreeNode::Utf8 { utf8, mode, executable } => (utf8.as_bytes().to_vec(), file_mode(path, mode, *executable)?),
+        TreeNode::Base64 { base64, mode, executable } => (decode_base64(path, base64)?, file_mode(path, mode, *executable)?),
+        TreeNode::Hex { hex, mode, executable } => (decode_hex(path, hex)?, file_mode(path, mode, *executable)?),
+        TreeNode::Symlink { symlink } => (symlink.as_bytes().to_vec(), MODE_SYMLINK),
+        TreeNode::Gitlink { .. } => return Err(Error::InvalidValue(format!("A gitlink isn't a file: {}", path))),
+        TreeNode::Deleted => return Ok(None),
+    };
+
+    Ok(Some(blob))
+}
+
+// Submodules with a url are added to base_gitmodules, which is the base tree's .gitmodules (if any)
+fn create_files_to_write(tree: &HashMap<String, TreeNode>, delete: &[String], base_gitmodules: Option<&str>,
+                         id_to_oid_lookup: &HashMap<String, Oid>) -> Result<HashMap<String, FileToWrite>, Error> {
     let mut files_to_write: HashMap<String, FileToWrite> = HashMap::new();
 
+    // (path, url) of the submodules to add to .gitmodules
+    let mut submodules: Vec<(&str, &str)> = Vec::new();
+
     for (path, node) in tree.iter() {
+        check_path(path)?;
+
         // split path by slashes
         let path_parts = split_path(path);
 
-        match node {
-            TreeNode::Utf8File(contents) => {
-                let contents_vec: Vec<u8> = contents.as_bytes().to_vec();
-                add_to_files_to_write(&mut files_to_write, path, &path_parts, contents_vec);
+        let file = match node {
+            TreeNode::Gitlink { gitlink, url } => {
+                if let Some(url) = url {
+                    submodules.push((path, url));
+                }
+                FileToWrite::Gitlink(gitlink_oid(gitlink, id_to_oid_lookup)?)
+            },
+            _ => match blob_contents(path, node)? {
+                Some((contents, mode)) => FileToWrite::File { contents, mode },
+                None => FileToWrite::Deleted
             }
+        };
+
+        add_to_files_to_write(&mut files_to_write, path, &path_parts, file)?;
+    }
+
+    for path in delete {
+        check_path(path)?;
+        add_to_files_to_write(&mut files_to_write, path, &split_path(path), FileToWrite::Deleted)?;
+    }
+
+    if !submodules.is_empty() {
+        if tree.contains_key(GITMODULES_PATH) {
+            return Err(Error::InvalidValue(format!("{} can't be provided when a gitlink has a url", GITMODULES_PATH)));
         }
+
+        // HashMap order is arbitrary. Sort so that .gitmodules is the same on every run.
+        submodules.sort();
+
+        let gitmodules = match base_gitmodules {
+            Some(base_gitmodules) => merge_gitmodules(base_gitmodules, &submodules),
+            None => create_gitmodules(&submodules)
+        };
+        let file = FileToWrite::File {
+            contents: gitmodules.into_bytes(),
+            mode: MODE_FILE
+        };
+        add_to_files_to_write(&mut files_to_write, GITMODULES_PATH, &[GITMODULES_PATH], file)?;
+    }
+
+    Ok(files_to_write)
+}
+
+// Validates a tree without writing it, e.g. path conflicts, file modes and contents
+pub fn check_tree(tree: &HashMap<String, TreeNode>, delete: &[String], id_to_oid_lookup: &HashMap<String, Oid>) -> Result<(), Error> {
+    create_files_to_write(tree, delete, None, id_to_oid_lookup).map(|_| ())
+}
+
+// Deleting a path (with null or the delete list) only makes sense on top of a base tree
+pub fn check_no_deletions(tree: &Option<HashMap<String, TreeNode>>, delete: &[String]) -> Result<(), Error> {
+    let deleted_in_tree = tree.iter().flatten()
+        .filter(|(_, node)| matches!(node, TreeNode::Deleted))
+        .map(|(path, _)| path);
+
+    match delete.iter().chain(deleted_in_tree).min() {
+        Some(path) => Err(Error::InvalidValue(format!("Only incremental trees can delete paths: {}", path))),
+        None => Ok(())
     }
+}
+
+// path => (mode, blob or gitlink hash)
+pub type FlatTree = BTreeMap<String, (i32, Oid)>;
+
+pub fn flatten_tree(repo: &Repository, tree: &Tree, prefix: &str, flat: &mut FlatTree) -> Result<(), Error> {
+    for entry in tree.iter() {
+        let path = format!("{}{}", prefix, String::from_utf8_lossy(entry.name_bytes()));
 
-    files_to_write
+        if entry.kind() == Some(ObjectType::Tree) {
+            flatten_tree(repo, &repo.find_tree(entry.id())?, &format!("{}/", path), flat)?;
+        } else {
+            flat.insert(path, (entry.filemode(), entry.id()));
+        }
+    }
+
+    Ok(())
 }
 
-fn create_tree(repo: &Repository, tree: &HashMap<String, TreeNode>) -> Result<Oid, Error> {
-    let files_to_write = create_files_to_write(tree);
+fn create_tree(repo: &Repository, base: Option<&Tree>, tree: &HashMap<String, TreeNode>, delete: &[String],
+               id_to_oid_lookup: &HashMap<String, Oid>) -> Result<Oid, Error> {
+    // Inherited submodules are kept in .gitmodules
+    let base_gitmodules = match base.and_then(|base| base.get_name(GITMODULES_PATH)) {
+        Some(entry) if entry.kind() == Some(ObjectType::Blob) => {
+            Some(String::from_utf8_lossy(repo.find_blob(entry.id())?.content()).into_owned())
+        },
+        _ => None
+    };
+    let files_to_write = create_files_to_write(tree, delete, base_gitmodules.as_deref(), id_to_oid_lookup)?;
 
     // build the tree objects once all the files are known
-    create_tree_recur(repo, &files_to_write)
+    create_tree_recur(repo, base, &files_to_write)
+}
+
+fn empty_tree(repo: &Repository) -> Result<Tree<'_>, Error> {
+    Ok(repo.find_tree(repo.treebuilder(None)?.write()?)?)
+}
+
+// The best common ancestors of the commit and any of the merged commits, like `git merge-base --all <commit> <merged>...`
+fn octopus_merge_bases(repo: &Repository, commit_oid: Oid, merged_oids: &[Oid]) -> Result<Vec<Oid>, Error> {
+    let mut candidates: Vec<Oid> = Vec::new();
+    for merged_oid in merged_oids {
+        match repo.merge_bases(commit_oid, *merged_oid) {
+            Ok(bases) => for base in bases.iter() {
+                if !candidates.contains(base) {
+                    candidates.push(*base);
+                }
+            },
+            // Unrelated histories
+            Err(ref e) if e.code() == ErrorCode::NotFound => (),
+            Err(e) => return Err(e.into())
+        }
+    }
+
+    // Ancestors of other candidates aren't the best ones
+    let mut bases = Vec::new();
+    for candidate in &candidates {
+        let mut best = true;
+        for other in &candidates {
+            if other != candidate && repo.graph_descendant_of(*other, *candidate)? {
+                best = false;
+                break;
+            }
+        }
+        if best {
+            bases.push(*candidate);
+        }
+    }
+    Ok(bases)
+}
+
+// The merge base tree of an octopus merge step. Like a recursive merge, several merge bases are merged into a
+// virtual one. Only the tree is built, no commits are written. Conflicts between the bases keep the first base's side.
+fn virtual_base_tree<'r>(repo: &'r Repository, bases: &[Oid]) -> Result<Tree<'r>, Error> {
+    let mut tree = match bases.first() {
+        Some(oid) => repo.find_commit(*oid)?.tree()?,
+        None => return empty_tree(repo)
+    };
+
+    for base in &bases[1..] {
+        let ancestor_tree = match repo.merge_base(bases[0], *base) {
+            Ok(oid) => repo.find_commit(oid)?.tree()?,
+            Err(ref e) if e.code() == ErrorCode::NotFound => empty_tree(repo)?,
+            Err(e) => return Err(e.into())
+        };
+        let mut merged = repo.merge_trees(&ancestor_tree, &tree, &repo.find_commit(*base)?.tree()?, None)?;
+
+        // Stage 2 is "ours"
+        let ours: Vec<IndexEntry> = merged.iter().filter(|entry| (entry.flags >> 12) & 0x3 == 2).collect();
+        remove_conflicts(&mut merged)?;
+        for mut entry in ours {
+            entry.flags &= !(0x3 << 12);
+            merged.add(&entry)?;
+        }
+        tree = repo.find_tree(merged.write_tree_to(repo)?)?;
+    }
+
+    Ok(tree)
+}
+
+// The ancestor and "their" trees of a three-way merge that applies the commit's changes, or reverses them
+pub fn pick_trees<'r>(repo: &'r Repository, commit: &Commit<'r>, revert: bool) -> Result<(Tree<'r>, Tree<'r>), Error> {
+    let parent_tree = match commit.parent_count() {
+        // A root commit adds every file
+        0 => repo.find_tree(repo.treebuilder(None)?.write()?)?,
+        1 => commit.parent(0)?.tree()?,
+        _ => return Err(Error::InvalidValue(format!("Can't {} a merge commit: {}", if revert { "revert" } else { "cherry-pick" }, commit.id())))
+    };
+
+    if revert {
+        Ok((commit.tree()?, parent_tree))
+    } else {
+        Ok((parent_tree, commit.tree()?))
+    }
+}
+
+// Removes all the conflicting entries from the index, and returns their paths
+fn remove_conflicts(index: &mut Index) -> Result<BTreeSet<String>, Error> {
+    // The conflict stage is stored in bits 12-13 of the flags. 0 means no conflict.
+    let conflicts: Vec<(String, i32)> = index.iter()
+        .map(|entry| (String::from_utf8_lossy(&entry.path).into_owned(), ((entry.flags >> 12) & 0x3) as i32))
+        .filter(|(_, stage)| *stage > 0)
+        .collect();
+
+    for (path, stage) in conflicts.iter() {
+        index.remove(Path::new(path), *stage)?;
+    }
+
+    Ok(conflicts.into_iter().map(|(path, _)| path).collect())
 }
 
 fn is_parent(parent: Oid, child: Oid, parent_to_child_ids: &HashMap<Oid, HashSet<Oid>>) -> bool {
@@ -135,8 +455,39 @@ fn is_parent(parent: Oid, child: Oid, parent_to_child_ids: &HashMap<Oid, HashSet
     false
 }
 
-pub struct Interpreter<'a> {
+// An explicitly provided date takes precedence over the clock's date
+fn resolve_date(date: &Option<String>, clock_date: Option<Time>) -> Result<Option<Time>, Error> {
+    match date {
+        Some(date) => Ok(Some(parse_date(date)?)),
+        None => Ok(clock_date)
+    }
+}
+
+fn new_signature(name: &str, email: &str, date: Option<Time>) -> Result<Signature<'static>, Error> {
+    let signature = match date {
+        Some(date) => Signature::new(name, email, &date)?,
+        None => Signature::now(name, email)?
+    };
+    Ok(signature)
+}
+
+// Anything that's not specified in the identity falls back to the defaults
+fn resolve_signature(identity: &Option<Identity>, default_name: &str, default_email: &str, default_date: Option<Time>) -> Result<Signature<'static>, Error> {
+    let identity = match identity {
+        Some(identity) => identity,
+        None => return new_signature(default_name, default_email, default_date)
+    };
+
+    let name  = identity.name.as_deref().unwrap_or(default_name);
+    let email = identity.email.as_deref().unwrap_or(default_email);
+    let date  = resolve_date(&identity.date, default_date)?;
+
+    new_signature(name, email, date)
+}
+
+pub struct Interpreter<'a, B: Backend = GitBackend> {
     repo: &'a Repository,
+    backend: B,
 
     // an Oid can be associated with one or more ids
     id_to_oid_lookup: HashMap<String, Oid>,
@@ -150,16 +501,30 @@ pub struct Interpreter<'a> {
     default_tagger_email: String,
 
     default_tree: Tree<'a>,
+
+    // If set, commit trees are patches on top of the first parent's tree
+    incremental: bool,
+
+    // If set, dates are generated by the clock instead of the system time
+    clock: Option<Clock>,
+    date_increment: i64,
 }
 
 impl Interpreter<'_> {
-    pub fn new(repo: &Repository) -> Result<Interpreter, Error> {
+    pub fn new(repo: &Repository) -> Result<Interpreter<'_>, Error> {
+        Interpreter::with_backend(repo, GitBackend::default())
+    }
+}
+
+impl<B: Backend> Interpreter<'_, B> {
+    pub fn with_backend(repo: &Repository, backend: B) -> Result<Interpreter<'_, B>, Error> {
         // Default tree has no files
-        let default_tree_oid = create_tree(repo, &HashMap::new())?;
+        let default_tree_oid = create_tree(repo, None, &HashMap::new(), &[], &HashMap::new())?;
         let default_tree = repo.find_tree(default_tree_oid)?;
 
         Ok(Interpreter {
             repo,
+            backend,
             id_to_oid_lookup: HashMap::new(),
             parent_to_child_ids: HashMap::new(),
 
@@ -171,16 +536,44 @@ impl Interpreter<'_> {
             default_tagger_email: DEFAULT_EMAIL.to_string(),
 
             default_tree,
+
+            incremental: false,
+
+            clock: None,
+            date_increment: DEFAULT_DATE_INCREMENT,
         })
     }
 
-    fn get_oid(&self, id: &String) -> Option<Oid> {
+    pub fn set_date(&mut self, date: Time) {
+        self.clock = Some(Clock::new(date, self.date_increment));
+    }
+
+    pub fn set_date_increment(&mut self, increment: i64) {
+        self.date_increment = increment;
+        if let Some(ref mut clock) = self.clock {
+            clock.set_increment(increment);
+        }
+    }
+
+    // Returns None if there's no deterministic clock (i.e. use the system time)
+    fn next_date(&mut self) -> Option<Time> {
+        self.clock.as_mut().map(|clock| clock.tick())
+    }
+
+    pub fn id_to_oid_lookup(&self) -> &HashMap<String, Oid> {
+        &self.id_to_oid_lookup
+    }
+
+    // Lets the backend finish its output (e.g. check out HEAD, or end the fast-import stream), and returns it
+    pub fn finish(mut self) -> Result<B, Error> {
+        self.backend.finish(self.repo)?;
+        Ok(self.backend)
+    }
+
+    fn get_oid(&self, id: &str) -> Result<Oid, Error> {
         match self.id_to_oid_lookup.get(id) {
-            Some(oid) => Some(*oid),
-            None => {
-                print_warning(&format!("ID doesn't exist: {}", id));
-                None
-            }
+            Some(oid) => Ok(*oid),
+            None => Err(Error::UnknownId(id.to_string()))
         }
     }
 
@@ -189,38 +582,129 @@ impl Interpreter<'_> {
     }
 
     fn set_parent_to_child(&mut self, parent: Oid, child: Oid) {
-        if !self.parent_to_child_ids.contains_key(&parent) {
-            self.parent_to_child_ids.insert(parent, HashSet::new());
-        }
-
-        let children = self.parent_to_child_ids.get_mut(&parent).unwrap();
+        let children = self.parent_to_child_ids.entry(parent).or_default();
         children.insert(child);
     }
 
-    fn commit(&mut self, id: &str, parent_oids: &[Oid], message: &str, tree: &Option<HashMap<String, TreeNode>>) -> Result<Oid, Error> {
+    fn commit_signatures(&mut self, author: &Option<Identity>, committer: &Option<Identity>,
+                         author_date: &Option<String>, committer_date: &Option<String>) -> Result<(Signature<'static>, Signature<'static>), Error> {
+        // The author and committer share one tick of the clock
+        let date = self.next_date();
+        let author_date    = resolve_date(author_date, date)?;
+        let committer_date = resolve_date(committer_date, date)?;
+
+        let author    = resolve_signature(author, &self.default_author_name, &self.default_author_email, author_date)?;
+        let committer = resolve_signature(committer, &self.default_committer_name, &self.default_committer_email, committer_date)?;
+
+        Ok((author, committer))
+    }
+
+    fn commit_tree(&self, parent_oids: &[Oid], tree: &Option<HashMap<String, TreeNode>>, delete: &[String],
+                   incremental: Option<bool>) -> Result<Oid, Error> {
         let repo = self.repo;
 
-        let tree = if let Some(tree) = tree {
-            // If a tree was provided, build it.
-            // It's a new value that gets freed at the end of this fn, so it's Cow::Owned
-            let tree_oid = create_tree(repo, tree)?;
-            Cow::Owned(repo.find_tree(tree_oid)?)
-        } else {
-            // If no tree was provided, use the default tree.
-            // It's an existing ref, so it's Cow::Borrowed
-            Cow::Borrowed(&self.default_tree)
+        if !incremental.unwrap_or(self.incremental) {
+            check_no_deletions(tree, delete)?;
+
+            return if let Some(tree) = tree {
+                // If a tree was provided, build it.
+                create_tree(repo, None, tree, &[], &self.id_to_oid_lookup)
+            } else {
+                // If no tree was provided, use the default tree.
+                Ok(self.default_tree.id())
+            };
+        }
+
+        // Incremental: the tree is a patch on top of the first parent's tree.
+        // Orphaned commits use the default tree instead.
+        let base = match parent_oids.first() {
+            Some(parent_oid) => repo.find_commit(*parent_oid)?.tree()?,
+            None => self.default_tree.clone()
         };
 
-        let author    = Signature::now(&self.default_author_name, &self.default_author_email)?;
-        let committer = Signature::now(&self.default_committer_name, &self.default_committer_email)?;
+        match tree {
+            Some(tree) => create_tree(repo, Some(&base), tree, delete, &self.id_to_oid_lookup),
+            None if !delete.is_empty() => create_tree(repo, Some(&base), &HashMap::new(), delete, &self.id_to_oid_lookup),
+            None => Ok(base.id())
+        }
+    }
+
+    // Merges the parents, like `git merge` does. libgit2's merge is recursive: criss-cross histories get a
+    // virtual merge base, and unrelated histories are merged as if the common ancestor was empty.
+    // Each parent after the second is merged into the result of the previous merges, like `git merge-octopus`:
+    // the merge base takes every parent that was already merged into account.
+    fn merge_tree(&self, parent_oids: &[Oid], resolutions: &Option<HashMap<String, TreeNode>>) -> Result<Oid, Error> {
+        let repo = self.repo;
+
+        let empty_resolutions = HashMap::new();
+        let resolutions = resolutions.as_ref().unwrap_or(&empty_resolutions);
 
-        let parent_objects_result: Result<Vec<Commit>, Error> = parent_oids.iter().map(|oid| {
+        let mut merged_tree_oid = repo.find_commit(parent_oids[0])?.tree_id();
+
+        for (index, their_oid) in parent_oids.iter().enumerate().skip(1) {
+            let their_commit = repo.find_commit(*their_oid)?;
+
+            let mut merged = if index == 1 {
+                repo.merge_commits(&repo.find_commit(parent_oids[0])?, &their_commit, None)?
+            } else {
+                let bases = octopus_merge_bases(repo, *their_oid, &parent_oids[..index])?;
+                let ancestor_tree = virtual_base_tree(repo, &bases)?;
+                repo.merge_trees(&ancestor_tree, &repo.find_tree(merged_tree_oid)?, &their_commit.tree()?, None)?
+            };
+            merged_tree_oid = self.resolve_conflicts(&mut merged, resolutions)?;
+        }
+
+        Ok(merged_tree_oid)
+    }
+
+    // Every conflicting path in the merged index must have a resolution
+    fn resolve_conflicts(&self, index: &mut Index, resolutions: &HashMap<String, TreeNode>) -> Result<Oid, Error> {
+        let repo = self.repo;
+
+        let conflicts = remove_conflicts(index)?;
+        let unresolved: Vec<String> = conflicts.into_iter()
+            .filter(|path| !resolutions.contains_key(path))
+            .collect();
+
+        if !unresolved.is_empty() {
+            return Err(Error::MergeConflict(unresolved));
+        }
+
+        // The resolutions are applied on top of the cleanly merged paths
+        let merged_tree = repo.find_tree(index.write_tree_to(repo)?)?;
+        create_tree(repo, Some(&merged_tree), resolutions, &[], &self.id_to_oid_lookup)
+    }
+
+    // Applies (or reverses) the commit's changes on top of the parent, like `git cherry-pick` and `git revert`
+    fn pick_tree(&self, commit_oid: Oid, parent_oid: Oid, revert: bool,
+                 resolutions: &Option<HashMap<String, TreeNode>>) -> Result<Oid, Error> {
+        let repo = self.repo;
+
+        let empty_resolutions = HashMap::new();
+        let resolutions = resolutions.as_ref().unwrap_or(&empty_resolutions);
+
+        let commit = repo.find_commit(commit_oid)?;
+        let (ancestor_tree, their_tree) = pick_trees(repo, &commit, revert)?;
+        let our_tree = repo.find_commit(parent_oid)?.tree()?;
+
+        let mut index = repo.merge_trees(&ancestor_tree, &our_tree, &their_tree, None)?;
+        self.resolve_conflicts(&mut index, resolutions)
+    }
+
+    fn commit(&mut self, id: &str, parent_oids: &[Oid], message: &str, tree_oid: Oid,
+              author: &Signature, committer: &Signature) -> Result<Oid, Error> {
+        let repo = self.repo;
+
+        let tree = repo.find_tree(tree_oid)?;
+
+        let parent_objects_result: Result<Vec<Commit>, git2::Error> = parent_oids.iter().map(|oid| {
             repo.find_commit(*oid)
         }).collect();
         let parent_objects: Vec<Commit> = parent_objects_result?;
         let parent_objects_refs: Vec<&Commit> = parent_objects.iter().collect();
 
-        let commit_oid = repo.commit(None, &author, &committer, message, &tree, &parent_objects_refs)?;
+        let commit_oid = repo.commit(None, author, committer, message, &tree, &parent_objects_refs)?;
+        self.backend.commit(repo, id, commit_oid)?;
 
         self.set_oid(id.to_string(), commit_oid);
         for parent_oid in parent_oids {
@@ -230,55 +714,115 @@ impl Interpreter<'_> {
         Ok(commit_oid)
     }
 
+    fn get_oids(&self, ids: &[String]) -> Result<Vec<Oid>, Error> {
+        ids.iter().map(|id| self.get_oid(id)).collect()
+    }
+
+    fn start_operation(&self, state: &OperationState) -> Result<(), Error> {
+        let repo = self.repo;
+
+        match state {
+            OperationState::RebaseMerge { onto, orig_head, head, branch, done, todo, .. } |
+            OperationState::RebaseApply { onto, orig_head, head, branch, done, todo } => {
+                let (apply, interactive) = match state {
+                    OperationState::RebaseMerge { interactive, .. } => (false, *interactive),
+                    _ => (true, false)
+                };
+                let onto = self.get_oid(onto)?;
+
+                start_rebase(repo, &Rebase {
+                    apply,
+                    interactive,
+                    onto,
+                    orig_head: self.get_oid(orig_head)?,
+                    head: match head { Some(head) => self.get_oid(head)?, None => onto },
+                    branch: branch.as_deref(),
+                    done: self.get_oids(done)?,
+                    todo: self.get_oids(todo)?,
+                })
+            },
+            OperationState::CherryPick { commit } => start_pick(repo, self.get_oid(commit)?, false),
+            OperationState::Revert { commit } => start_pick(repo, self.get_oid(commit)?, true),
+            OperationState::Bisect { bad, good, skip, head } => {
+                let head = match head { Some(head) => Some(self.get_oid(head)?), None => None };
+                start_bisect(repo, self.get_oid(bad)?, &self.get_oids(good)?, &self.get_oids(skip)?, head)
+            },
+        }
+    }
+
+    // Creates the branches, and lightweight tags, of a commit or merge command
+    fn assign_refs(&mut self, commit_oid: Oid, branches: &Option<Vec<String>>, tags: &Option<Vec<String>>) -> Result<(), Error> {
+        for name in branches.iter().flatten() {
+            self.backend.branch(self.repo, name, commit_oid)?;
+        }
+        for name in tags.iter().flatten() {
+            self.backend.lightweight_tag(self.repo, name, commit_oid)?;
+        }
+        Ok(())
+    }
+
+    // Errors include the index of the failing command
+    pub fn interpret_commands(&mut self, commands: &[Command]) -> Result<(), Error> {
+        for (index, command) in commands.iter().enumerate() {
+            self.interpret_command(command).map_err(|e| e.in_command(index))?;
+        }
+
+        Ok(())
+    }
+
     pub fn interpret_command(&mut self, command: &Command) -> Result<(), Error> {
         let repo = self.repo;
 
         match &command {
-            Command::Commit { id, message, parents, tree, branches, tags } => {
+            Command::Commit { id, message, parents, tree, delete, incremental, author, committer, author_date, committer_date, branches, tags } => {
                 // Resolve { parents: [...] } to git2-rs Commit objects
-                let parent_oids: Vec<Oid> = parents.iter().flat_map(|parent_id| {
+                let parent_oids: Vec<Oid> = parents.iter().map(|parent_id| {
                     self.get_oid(parent_id)
-                }).collect();
+                }).collect::<Result<_, _>>()?;
 
                 let used_message: &str = if let Some(ref m) = message {
                     // Use the provided message
                     m
                 } else {
                     // Use the commit's ID as the message
-                    &id
+                    id
                 };
 
-                // Commit!
-                let commit_oid = self.commit(id, &parent_oids, used_message, tree)?;
+                let tree_oid = self.commit_tree(&parent_oids, tree, delete, *incremental)?;
+                let (author, committer) = self.commit_signatures(author, committer, author_date, committer_date)?;
 
-                // Create branches
-                if let Some(branches) = branches {
-                    let commit = repo.find_commit(commit_oid)?;
-                    for name in branches {
-                        repo.branch(name, &commit, true /* force, even if branch exists */)?;
-                    }
-                }
+                // Commit!
+                let commit_oid = self.commit(id, &parent_oids, used_message, tree_oid, &author, &committer)?;
 
-                // Create lightweight tags
-                if let Some(tags) = tags {
-                    let commit = repo.find_object(commit_oid, None)?;
-                    for name in tags {
-                        repo.tag_lightweight(name, &commit, true /* force, even if tag exists */)?;
-                    }
-                }
+                self.assign_refs(commit_oid, branches, tags)?;
             },
             
-            Command::Merge { id, commits, message, tree, branches, tags, no_ff } => {
+            Command::Merge { id, commits, message, tree, delete, incremental, author, committer, author_date, committer_date,
+                             branches, tags, strategy, resolutions, no_ff, in_progress } => {
                 if commits.is_empty() {
-                    panic!("Commits cannot be empty");
+                    return Err(Error::EmptyMerge);
                 }
 
-                let mut set_of_oids = HashSet::new();
+                // Remove duplicates, but keep the order of the commits.
+                // The order determines the parents of the merge commit, so it must be stable.
+                let mut vec_of_oids: Vec<Oid> = Vec::new();
                 for c_id in commits {
-                    set_of_oids.insert(self.get_oid(c_id).unwrap());
+                    let oid = self.get_oid(c_id)?;
+                    if !vec_of_oids.contains(&oid) {
+                        vec_of_oids.push(oid);
+                    }
                 }
 
-                let vec_of_oids: Vec<Oid> = set_of_oids.into_iter().collect();
+                // The index and the work tree are left for the user, instead of a commit
+                if *in_progress {
+                    if tree.is_some() || !delete.is_empty() || incremental.is_some() || strategy.is_some() || resolutions.is_some() ||
+                       author.is_some() || committer.is_some() || author_date.is_some() || committer_date.is_some() || *no_ff ||
+                       branches.is_some() || tags.is_some() {
+                        return Err(Error::InvalidValue("An in-progress merge can't have a tree, delete list, incremental, strategy, resolutions, author, committer, dates, no_ff, branches or tags".to_string()));
+                    }
+                    self.backend.checkout(repo)?;
+                    return start_merge(repo, &vec_of_oids, message.as_deref());
+                }
 
                 // None: don't fast-forward
                 // Some(to_oid): yes, and fast-forward to this oid
@@ -311,50 +855,97 @@ impl Interpreter<'_> {
                         format!("Merge commits {}", list)
                     };
 
+                    let tree_oid = match strategy {
+                        Some(MergeStrategy::Recursive) => {
+                            if tree.is_some() || !delete.is_empty() {
+                                return Err(Error::InvalidValue("A merge with a strategy can't have a tree or delete list. Use resolutions instead.".to_string()));
+                            }
+                            self.merge_tree(&vec_of_oids, resolutions)?
+                        },
+                        None => {
+                            if resolutions.is_some() {
+                                return Err(Error::InvalidValue("Resolutions can only be used with a merge strategy".to_string()));
+                            }
+                            self.commit_tree(&vec_of_oids, tree, delete, *incremental)?
+                        }
+                    };
+                    let (author, committer) = self.commit_signatures(author, committer, author_date, committer_date)?;
+
                     // Commit!
-                    self.commit(id, &vec_of_oids, &used_message, tree)?
+                    self.commit(id, &vec_of_oids, &used_message, tree_oid, &author, &committer)?
                 };
 
-                // Create branches
-                if let Some(branches) = branches {
-                    let commit = repo.find_commit(commit_oid)?;
-                    for name in branches {
-                        repo.branch(name, &commit, true /* force, even if branch exists */)?;
-                    }
+                self.assign_refs(commit_oid, branches, tags)?;
+            },
+            
+            Command::CherryPick { id, commit, parent, message, resolutions, author, committer, author_date, committer_date, branches, tags } |
+            Command::Revert { id, commit, parent, message, resolutions, author, committer, author_date, committer_date, branches, tags } => {
+                let revert = matches!(command, Command::Revert { .. });
+                let commit_oid = self.get_oid(commit)?;
+                let parent_oid = self.get_oid(parent)?;
+
+                let tree_oid = self.pick_tree(commit_oid, parent_oid, revert, resolutions)?;
+                let (mut new_author, committer) = self.commit_signatures(author, committer, author_date, committer_date)?;
+
+                let picked = repo.find_commit(commit_oid)?;
+                let used_message = match message {
+                    Some(message) => message.to_string(),
+                    None if revert => revert_message(&picked),
+                    None => format!("{}\n\n(cherry picked from commit {})\n", picked.message().unwrap_or("").trim_end(), commit_oid)
+                };
+                // Like Git, a cherry-pick keeps the original author. Only the fields that are given replace its fields.
+                if !revert {
+                    let original = picked.author();
+                    let date = resolve_date(author_date, Some(original.when()))?;
+                    new_author = resolve_signature(author, &String::from_utf8_lossy(original.name_bytes()),
+                                                   &String::from_utf8_lossy(original.email_bytes()), date)?;
                 }
 
-                // Create lightweight tags
-                if let Some(tags) = tags {
-                    let commit = repo.find_object(commit_oid, None)?;
-                    for name in tags {
-                        repo.tag_lightweight(name, &commit, true /* force, even if tag exists */)?;
-                    }
-                }
+                // Commit!
+                let new_oid = self.commit(id, &[parent_oid], &used_message, tree_oid, &new_author, &committer)?;
+
+                self.assign_refs(new_oid, branches, tags)?;
             },
-            
+
             Command::Branch { name, on } => {
-                if let Some(commit_oid) = self.get_oid(on) {
-                    let commit = repo.find_commit(commit_oid)?;
+                let commit_oid = self.get_oid(on)?;
 
-                    repo.branch(name, &commit, true /* force, even if branch exists */)?;
+                self.backend.branch(repo, name, commit_oid)?;
 
-                    // repo.reference(&format!("refs/remotes/github/{}", name), commit_oid, true, "test")?;
-                }
+                // repo.reference(&format!("refs/remotes/github/{}", name), commit_oid, true, "test")?;
             },
             
-            Command::Tag { name, on, lightweight } => {
-                if let Some(commit_oid) = self.get_oid(on) {
-                    let commit = repo.find_object(commit_oid, None)?;
+            Command::Tag { name, on, tagger, message, lightweight } => {
+                let commit_oid = self.get_oid(on)?;
 
-                    if *lightweight {
-                        // Lightweight tag
-                        repo.tag_lightweight(name, &commit, true /* force, even if tag exists */)?;
-                    } else {
-                        // Annotated tag
-                        let tagger = Signature::now(&self.default_tagger_name, &self.default_tagger_email)?;
+                if *lightweight {
+                    self.backend.lightweight_tag(repo, name, commit_oid)?;
+                } else {
+                    let date = self.next_date();
+                    let tagger = resolve_signature(tagger, &self.default_tagger_name, &self.default_tagger_email, date)?;
 
-                        repo.tag(name, &commit, &tagger, "Tag message", true /* force, even if tag exists */)?;
-                    }
+                    self.backend.annotated_tag(repo, name, commit_oid, &tagger, message.as_deref().unwrap_or("Tag message"))?;
+                }
+            },
+
+            Command::Worktree { staged, unstaged, untracked, ignored } => {
+                self.backend.checkout(repo)?;
+                apply_worktree(repo, staged, unstaged, untracked, ignored)?;
+            },
+
+            Command::State(state) => {
+                self.backend.checkout(repo)?;
+                self.start_operation(state)?;
+            },
+
+            Command::Head { branch, on } => {
+                match (branch, on) {
+                    (Some(branch), None) => self.backend.symbolic_head(repo, branch)?,
+                    (None, Some(on)) => {
+                        let commit_oid = self.get_oid(on)?;
+                        self.backend.detached_head(repo, commit_oid)?;
+                    },
+                    _ => return Err(Error::InvalidValue("A head command needs either a branch or a commit (\"on\")".to_string()))
                 }
             },
 
@@ -362,7 +953,8 @@ impl Interpreter<'_> {
                               author_name,    author_email,
                               committer_name, committer_email,
                               tagger_name,    tagger_email,
-                              tree } => {
+                              date,           date_increment,
+                              tree,           incremental } => {
                 //
                 if let Some(all_name) = all_name {
                     self.default_author_name    = all_name.clone();
@@ -396,12 +988,24 @@ impl Interpreter<'_> {
                     self.default_tagger_email = tagger_email.clone();
                 }
 
+                // Set the increment first, so that it applies to the new clock
+                if let Some(date_increment) = date_increment {
+                    self.set_date_increment(*date_increment);
+                }
+                if let Some(date) = date {
+                    self.set_date(parse_date(date)?);
+                }
 
+                check_no_deletions(tree, &[])?;
                 if let Some(tree) = tree {
-                    let tree_oid = create_tree(repo, &tree)?;
+                    let tree_oid = create_tree(repo, None, tree, &[], &self.id_to_oid_lookup)?;
                     let tree = repo.find_tree(tree_oid)?;
                     self.default_tree = tree;
                 }
+
+                if let Some(incremental) = incremental {
+                    self.incremental = *incremental;
+                }
             },
             
         };
@@ -416,6 +1020,8 @@ impl Interpreter<'_> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use super::super::builder::RepoBuilder;
+    use super::super::test_fixtures::{with_command, file_at};
 
     #[test]
     fn split_path_test() {
@@ -428,4 +1034,356 @@ mod tests {
         assert_eq!(split_path("path/to/file"),
                    vec!["path", "to", "file"]);
     }
-}
\ No newline at end of file
+
+    fn file_contents(files_to_write: &HashMap<String, FileToWrite>, name: &str) -> Vec<u8> {
+        match files_to_write.get(name) {
+            Some(FileToWrite::File { contents, .. }) => contents.clone(),
+            other => panic!("Expected a file: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn create_files_to_write_binary_test() {
+        let mut tree = HashMap::new();
+        tree.insert("text".to_string(),   TreeNode::Utf8File("hi".to_string()));
+        tree.insert("base64".to_string(), TreeNode::Base64 { base64: "AAEC/w==".to_string(), mode: None, executable: false });
+        tree.insert("hex".to_string(),    TreeNode::Hex { hex: "DEADbeef".to_string(), mode: None, executable: false });
+
+        let files_to_write = create_files_to_write(&tree, &[], None, &HashMap::new()).unwrap();
+        assert_eq!(file_contents(&files_to_write, "text"),   b"hi".to_vec());
+        assert_eq!(file_contents(&files_to_write, "base64"), vec![0x00, 0x01, 0x02, 0xff]);
+        assert_eq!(file_contents(&files_to_write, "hex"),    vec![0xde, 0xad, 0xbe, 0xef]);
+
+        let mut tree = HashMap::new();
+        tree.insert("bad".to_string(), TreeNode::Hex { hex: "zz".to_string(), mode: None, executable: false });
+        assert!(create_files_to_write(&tree, &[], None, &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn create_files_to_write_conflict_test() {
+        let file = || FileToWrite::File { contents: vec![], mode: MODE_FILE };
+
+        let mut files_to_write = HashMap::new();
+        add_to_files_to_write(&mut files_to_write, "a/b", &["a", "b"], file()).unwrap();
+
+        match add_to_files_to_write(&mut files_to_write, "a/b", &["a", "b"], file()) {
+            Err(Error::DuplicatePath(path)) => assert_eq!(path, "a/b"),
+            other => panic!("Expected a duplicate path: {:?}", other)
+        }
+
+        match add_to_files_to_write(&mut files_to_write, "a/b/c/d", &["a", "b", "c", "d"], file()) {
+            Err(Error::FileDirectoryConflict(path)) => assert_eq!(path, "a/b"),
+            other => panic!("Expected a file/directory conflict: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn create_files_to_write_symlink_test() {
+        let mut tree = HashMap::new();
+        tree.insert("dir/link".to_string(), TreeNode::Symlink { symlink: "../target".to_string() });
+
+        let files_to_write = create_files_to_write(&tree, &[], None, &HashMap::new()).unwrap();
+        match files_to_write.get("dir") {
+            Some(FileToWrite::Directory(dir)) => match dir.get("link") {
+                Some(FileToWrite::File { contents, mode }) => {
+                    assert_eq!(contents, b"../target");
+                    assert_eq!(*mode, 0o120000);
+                },
+                other => panic!("Expected a file: {:?}", other)
+            },
+            other => panic!("Expected a directory: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn create_files_to_write_gitlink_test() {
+        let oid = Oid::from_str("e83c5163316f89bfbde7d9ab23ca2e25604af290").unwrap();
+        let mut id_to_oid_lookup = HashMap::new();
+        id_to_oid_lookup.insert("a".to_string(), oid);
+
+        let mut tree = HashMap::new();
+        tree.insert("libs/b".to_string(), TreeNode::Gitlink {
+            gitlink: GitlinkTarget::Id { id: "a".to_string() },
+            url: Some("https://example.org/b.git".to_string())
+        });
+        tree.insert("libs/a".to_string(), TreeNode::Gitlink {
+            gitlink: GitlinkTarget::Hash("e83c5163316f89bfbde7d9ab23ca2e25604af290".to_string()),
+            url: Some("../a.git".to_string())
+        });
+
+        let files_to_write = create_files_to_write(&tree, &[], None, &id_to_oid_lookup).unwrap();
+        match files_to_write.get("libs") {
+            Some(FileToWrite::Directory(dir)) => {
+                assert!(matches!(dir.get("a"), Some(FileToWrite::Gitlink(o)) if *o == oid));
+                assert!(matches!(dir.get("b"), Some(FileToWrite::Gitlink(o)) if *o == oid));
+            },
+            other => panic!("Expected a directory: {:?}", other)
+        }
+        assert_eq!(String::from_utf8(file_contents(&files_to_write, ".gitmodules")).unwrap(),
+                   "[submodule \"libs/a\"]\n\tpath = libs/a\n\turl = ../a.git\n\
+                    [submodule \"libs/b\"]\n\tpath = libs/b\n\turl = https://example.org/b.git\n");
+
+        // Unknown IDs
+        let mut tree = HashMap::new();
+        tree.insert("c".to_string(), TreeNode::Gitlink { gitlink: GitlinkTarget::Id { id: "c".to_string() }, url: None });
+        assert!(create_files_to_write(&tree, &[], None, &id_to_oid_lookup).is_err());
+    }
+
+    #[test]
+    fn file_mode_test() {
+        assert_eq!(file_mode("f", &None, false).unwrap(), 0o100644);
+        assert_eq!(file_mode("f", &None, true).unwrap(),  0o100755);
+        assert_eq!(file_mode("f", &Some(FileMode::Number(100755)), false).unwrap(), 0o100755);
+        assert_eq!(file_mode("f", &Some(FileMode::String("100755".to_string())), true).unwrap(), 0o100755);
+        assert!(matches!(file_mode("f", &Some(FileMode::String("100644".to_string())), true), Err(Error::InvalidValue(_))));
+
+        assert!(file_mode("f", &Some(FileMode::Number(120000)), false).is_err());
+        assert!(file_mode("f", &Some(FileMode::String("755".to_string())), false).is_err());
+        assert!(file_mode("f", &Some(FileMode::String("rwx".to_string())), false).is_err());
+    }
+
+    fn tree_from_json(json: &str) -> HashMap<String, TreeNode> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    // Creates the tree, and lists its files
+    fn create_flat_tree(repo: &Repository, base: Option<&Tree>, tree: &str, delete: &[&str]) -> Result<(Oid, Vec<String>), Error> {
+        let delete: Vec<String> = delete.iter().map(|path| path.to_string()).collect();
+        let tree_oid = create_tree(repo, base, &tree_from_json(tree), &delete, &HashMap::new())?;
+
+        let mut flat = FlatTree::new();
+        flatten_tree(repo, &repo.find_tree(tree_oid)?, "", &mut flat)?;
+        Ok((tree_oid, flat.into_keys().collect()))
+    }
+
+    #[test]
+    fn create_tree_base_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init_bare(dir.path()).unwrap();
+
+        let (base_oid, _) = create_flat_tree(&repo, None, r#"{
+            "README": "Hello", "src/main.c": "", "src/lib/a.c": "", "src/lib/b.c": "", "docs/guide.md": "", "build/out": ""
+        }"#, &[]).unwrap();
+        let base = repo.find_tree(base_oid).unwrap();
+
+        // Patching keeps the other files of the base
+        let (_, files) = create_flat_tree(&repo, Some(&base), r#"{ "README": "Changed", "src/lib/c.c": "" }"#, &[]).unwrap();
+        assert_eq!(files, vec!["README", "build/out", "docs/guide.md", "src/lib/a.c", "src/lib/b.c", "src/lib/c.c", "src/main.c"]);
+
+        // Nested deletes, with null and the delete list. Empty directories are removed.
+        let (_, files) = create_flat_tree(&repo, Some(&base), r#"{ "src/lib/a.c": null, "missing.txt": null }"#, &["src/lib/b.c", "docs"]).unwrap();
+        assert_eq!(files, vec!["README", "build/out", "src/main.c"]);
+
+        // A directory is replaced with a file, and a file with a directory
+        let (_, files) = create_flat_tree(&repo, Some(&base), r#"{ "src": "Now a file", "README/new.txt": "" }"#, &[]).unwrap();
+        assert_eq!(files, vec!["README/new.txt", "build/out", "docs/guide.md", "src"]);
+    }
+
+    #[test]
+    fn create_tree_gitmodules_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init_bare(dir.path()).unwrap();
+        let hash = "0123456789012345678901234567890123456789";
+
+        let (base_oid, _) = create_flat_tree(&repo, None, &format!(r#"{{
+            "lib/one": {{ "gitlink": "{hash}", "url": "https://example.org/one.git" }},
+            "lib/two": {{ "gitlink": "{hash}", "url": "https://example.org/two.git" }}
+        }}"#, hash = hash), &[]).unwrap();
+        let base = repo.find_tree(base_oid).unwrap();
+
+        // "lib/two" moves, and "lib/three" is added. "lib/one" is inherited.
+        let (tree_oid, _) = create_flat_tree(&repo, Some(&base), &format!(r#"{{
+            "lib/two": {{ "gitlink": "{hash}", "url": "https://example.org/moved.git" }},
+            "lib/three": {{ "gitlink": "{hash}", "url": "https://example.org/three.git" }}
+        }}"#, hash = hash), &[]).unwrap();
+
+        let tree = repo.find_tree(tree_oid).unwrap();
+        let gitmodules = repo.find_blob(tree.get_name(GITMODULES_PATH).unwrap().id()).unwrap();
+        assert_eq!(std::str::from_utf8(gitmodules.content()).unwrap(), concat!(
+            "[submodule \"lib/one\"]\n\tpath = lib/one\n\turl = https://example.org/one.git\n",
+            "[submodule \"lib/three\"]\n\tpath = lib/three\n\turl = https://example.org/three.git\n",
+            "[submodule \"lib/two\"]\n\tpath = lib/two\n\turl = https://example.org/moved.git\n",
+        ));
+    }
+
+    #[test]
+    fn non_incremental_deletions_test() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let result = RepoBuilder::new(dir.path().join("null")).bare(true)
+            .commit("a").tree_node("README", TreeNode::Deleted)
+            .build();
+        assert!(result.is_err());
+
+        let result = RepoBuilder::new(dir.path().join("delete")).bare(true)
+            .commit("a").delete("README")
+            .build();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn cherry_pick_test() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let generated = with_command(&dir.path().join("original"), r#"{ "type": "cherry-pick", "id": "p", "commit": "b", "parent": "m" }"#).unwrap();
+        let p = generated.repository().find_commit(generated.oid("p").unwrap()).unwrap();
+
+        assert_eq!(file_at(&generated, "p", "README").unwrap(), "ONE\ntwo\nTHREE\n");
+        assert_eq!(file_at(&generated, "p", "b.txt").unwrap(), "B\n");
+        assert_eq!(p.parent_ids().collect::<Vec<_>>(), vec![generated.oid("m").unwrap()]);
+        assert_eq!(p.message().unwrap(), format!("Change one\n\n(cherry picked from commit {})\n", generated.oid("b").unwrap()));
+
+        // The author is kept, and the committer is new
+        assert_eq!(p.author().name(), Some("Original"));
+        assert_eq!(p.author().when().seconds(), 1500000000);
+        assert_ne!(p.committer().name(), Some("Original"));
+
+        // The given fields replace the original author's fields
+        let generated = with_command(&dir.path().join("author"), r#"{
+            "type": "cherry-pick", "id": "p", "commit": "b", "parent": "m", "author": { "email": "picker@example.com" }, "author_date": "1600000000 +0000"
+        }"#).unwrap();
+        let author = generated.repository().find_commit(generated.oid("p").unwrap()).unwrap().author().to_owned();
+        assert_eq!(author.name(), Some("Original"));
+        assert_eq!(author.email(), Some("picker@example.com"));
+        assert_eq!(author.when().seconds(), 1600000000);
+    }
+
+    #[test]
+    fn revert_test() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // Reverting "b" on top of "m" removes b.txt, and keeps m's change
+        let generated = with_command(dir.path(), r#"{ "type": "revert", "id": "r", "commit": "b", "parent": "m", "branches": ["reverted"] }"#).unwrap();
+        let r = generated.repository().find_commit(generated.oid("r").unwrap()).unwrap();
+
+        assert_eq!(file_at(&generated, "r", "README").unwrap(), "one\ntwo\nTHREE\n");
+        assert_eq!(file_at(&generated, "r", "b.txt"), None);
+        assert_eq!(r.message().unwrap(), format!("Revert \"Change one\"\n\nThis reverts commit {}.\n", generated.oid("b").unwrap()));
+        assert_eq!(generated.repository().refname_to_id("refs/heads/reverted").unwrap(), r.id());
+    }
+
+    #[test]
+    fn pick_conflict_test() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // "c" changes the line that "m" doesn't have yet
+        let result = with_command(&dir.path().join("conflict"), r#"{ "type": "cherry-pick", "id": "p", "commit": "c", "parent": "m" }"#);
+        assert_eq!(result.err().unwrap().to_string(), "command at index 4: Merge conflicts without resolutions: README");
+
+        let generated = with_command(&dir.path().join("resolved"), r#"{
+            "type": "cherry-pick", "id": "p", "commit": "c", "parent": "m", "message": "Picked", "resolutions": { "README": "1\ntwo\nTHREE\n" }
+        }"#).unwrap();
+        assert_eq!(file_at(&generated, "p", "README").unwrap(), "1\ntwo\nTHREE\n");
+        assert_eq!(file_at(&generated, "p", "b.txt"), None);
+    }
+
+    // b changes the first line, c changes the last line, and d changes the first line too
+    fn merge_repo(path: &Path) -> RepoBuilder {
+        RepoBuilder::new(path).bare(true)
+            .commit("a").file("README", "one\ntwo\nthree\n")
+            .commit("b").parents(["a"]).file("README", "ONE\ntwo\nthree\n")
+            .commit("c").parents(["a"]).file("README", "one\ntwo\nTHREE\n")
+            .commit("d").parents(["a"]).file("README", "1\ntwo\nthree\n")
+            .finish()
+    }
+
+    #[test]
+    fn merge_tree_test() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let generated = merge_repo(dir.path())
+            .merge("m", ["b", "c"]).strategy(MergeStrategy::Recursive)
+            .build()
+            .unwrap();
+
+        assert_eq!(file_at(&generated, "m", "README").unwrap(), "ONE\ntwo\nTHREE\n");
+    }
+
+    #[test]
+    fn merge_tree_conflict_test() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let result = merge_repo(&dir.path().join("conflict"))
+            .merge("m", ["b", "d"]).strategy(MergeStrategy::Recursive)
+            .build();
+        assert!(matches!(result, Err(Error::Command { index: 4, error }) if matches!(&*error, Error::MergeConflict(paths) if paths == &vec!["README".to_string()])));
+
+        let generated = merge_repo(&dir.path().join("resolved"))
+            .merge("m", ["b", "d"]).strategy(MergeStrategy::Recursive).resolution("README", "Resolved\n")
+            .build()
+            .unwrap();
+        assert_eq!(file_at(&generated, "m", "README").unwrap(), "Resolved\n");
+
+        // Resolutions are only used with a strategy
+        let result = merge_repo(&dir.path().join("no-strategy"))
+            .merge("m", ["b", "d"]).resolution("README", "Resolved\n")
+            .build();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn remove_conflicts_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let generated = merge_repo(dir.path()).build().unwrap();
+        let repo = generated.repository();
+        let tree = |id: &str| repo.find_commit(generated.oid(id).unwrap()).unwrap().tree().unwrap();
+
+        let mut index = repo.merge_trees(&tree("a"), &tree("b"), &tree("d"), None).unwrap();
+        assert!(index.has_conflicts());
+
+        let conflicts = remove_conflicts(&mut index).unwrap();
+        assert_eq!(conflicts.into_iter().collect::<Vec<_>>(), vec!["README".to_string()]);
+        assert!(!index.has_conflicts());
+        assert_eq!(index.len(), 0);
+    }
+
+    #[test]
+    fn octopus_merge_tree_test() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // "e" builds on "c", so it only conflicts with "c" if the merge base ignores that "c" was already merged
+        let generated = merge_repo(dir.path())
+            .commit("e").parents(["c"]).file("README", "one\ntwo\n3\n")
+            .merge("m", ["b", "c", "e"]).strategy(MergeStrategy::Recursive)
+            .build()
+            .unwrap();
+
+        assert_eq!(file_at(&generated, "m", "README").unwrap(), "ONE\ntwo\n3\n");
+        let repo = generated.repository();
+        let m = repo.find_commit(generated.oid("m").unwrap()).unwrap();
+        assert_eq!(m.parent_count(), 3);
+
+        // "a" is an ancestor of "c", so only "c" is a merge base of "e" and the merged "b" and "c"
+        let oid = |id: &str| generated.oid(id).unwrap();
+        assert_eq!(octopus_merge_bases(repo, oid("e"), &[oid("b"), oid("c")]).unwrap(), vec![oid("c")]);
+
+        // Several merge bases are merged into a virtual one. When they conflict ("b" and "d"), the first one wins.
+        let tree = virtual_base_tree(repo, &[oid("b"), oid("c")]).unwrap();
+        assert_eq!(repo.find_blob(tree.get_name("README").unwrap().id()).unwrap().content(), b"ONE\ntwo\nTHREE\n");
+        let tree = virtual_base_tree(repo, &[oid("b"), oid("d")]).unwrap();
+        assert_eq!(repo.find_blob(tree.get_name("README").unwrap().id()).unwrap().content(), b"ONE\ntwo\nthree\n");
+
+        // No intermediate commits are written: a, b, c, d, e and m
+        let mut commits = 0;
+        repo.odb().unwrap().foreach(|oid| {
+            commits += (repo.find_object(*oid, Some(ObjectType::Commit)).is_ok()) as usize;
+            true
+        }).unwrap();
+        assert_eq!(commits, 6);
+    }
+
+    #[test]
+    fn unrelated_merge_tree_test() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let generated = RepoBuilder::new(dir.path()).bare(true)
+            .commit("a").file("a.txt", "A")
+            .commit("b").file("b.txt", "B")
+            .merge("m", ["a", "b"]).strategy(MergeStrategy::Recursive)
+            .build()
+            .unwrap();
+
+        assert_eq!(file_at(&generated, "m", "a.txt").unwrap(), "A");
+        assert_eq!(file_at(&generated, "m", "b.txt").unwrap(), "B");
+    }
+}
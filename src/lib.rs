@@ -0,0 +1,71 @@
+// Generates Git repositories for testing purposes.
+//
+// The input is a list of `Command`s, which are run in order by an `Interpreter`.
+// Rust test suites can use `RepoBuilder` to generate fixture repositories in-process.
+
+use git2::Repository;
+use std::path::Path;
+
+mod backend;
+pub use backend::{Backend, GitBackend, FastImportBackend};
+
+mod can_fastforward;
+
+mod check;
+pub use check::check_commands;
+
+mod command;
+pub use command::{Command, TreeNode, Identity, FileMode, GitlinkTarget, MergeStrategy, OperationState};
+
+mod date;
+pub use date::parse_date;
+
+mod dsl;
+pub use dsl::parse_dsl;
+
+mod error;
+pub use error::Error;
+
+mod export;
+mod fast_export;
+pub use export::{ExportOptions, export_repository};
+pub use fast_export::parse_fast_export;
+
+mod format;
+pub use format::{Format, parse_commands};
+
+mod graph;
+pub use graph::{GraphFormat, render_graph};
+
+mod interpreter;
+pub use interpreter::Interpreter;
+
+mod state;
+
+mod temporary;
+pub use temporary::TemporaryRepository;
+
+mod verify;
+pub use verify::{Mismatch, TreeChange, verify_repository};
+
+mod worktree;
+
+mod builder;
+pub use builder::{RepoBuilder, CommitBuilder, MergeBuilder, GeneratedRepo};
+
+#[cfg(test)]
+mod test_fixtures;
+
+// Re-exported, so that users can refer to the Repository and Oid types.
+pub use git2;
+
+// Creates the repository if it doesn't exist.
+pub fn init_repository<P: AsRef<Path>>(path: P, bare: bool) -> Result<Repository, Error> {
+    let repo = if bare {
+        Repository::init_bare(path)?
+    } else {
+        Repository::init(path)?
+    };
+
+    Ok(repo)
+}
@@ -1,43 +1,281 @@
 #[macro_use]
 extern crate clap;
 
-use clap::{Arg, App};
+use clap::{Arg, App, AppSettings, ArgMatches, SubCommand};
 
-use git2::{Repository, Error};
-use std::io::{self, Read};
+use git2::{Repository, Oid};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
 
-mod command;
-use command::Command;
+use generate_git_repo::{Command, check_commands, Interpreter, Backend, FastImportBackend, TemporaryRepository, Error, Format, GraphFormat, ExportOptions, render_graph, parse_commands, parse_fast_export, parse_date, init_repository, export_repository, verify_repository};
 
-mod interpreter;
-use interpreter::Interpreter;
+// The commit ids and refs as JSON, so that tests can look up the commit hashes.
+// e.g. { "ids": { "a": "<commit hash>" }, "refs": { "refs/heads/master": "<commit hash>" } }
+fn ids_json(repo: &Repository, id_to_oid_lookup: &HashMap<String, Oid>) -> Result<String, Error> {
+    // BTreeMap, so that the output is sorted
+    let ids: BTreeMap<&String, String> = id_to_oid_lookup.iter()
+        .map(|(id, oid)| (id, oid.to_string()))
+        .collect();
 
-mod can_fastforward;
+    // Only the branches and tags of the generated commits, peeled to the commit (like "ids"), so that
+    // annotated tags, scratch refs and refs that were already in the repository don't show up.
+    let generated: HashSet<&Oid> = id_to_oid_lookup.values().collect();
+    let mut refs: BTreeMap<String, String> = BTreeMap::new();
+    for reference in repo.references()? {
+        let reference = reference?;
+        if !reference.is_branch() && !reference.is_tag() {
+            continue;
+        }
+        if let (Some(name), Ok(commit)) = (reference.name(), reference.peel_to_commit()) {
+            if generated.contains(&commit.id()) {
+                refs.insert(name.to_string(), commit.id().to_string());
+            }
+        }
+    }
+
+    let json = serde_json::json!({ "ids": ids, "refs": refs });
+    Ok(format!("{}\n", serde_json::to_string_pretty(&json).unwrap()))
+}
+
+fn emit_ids(repo: &Repository, interpreter: &Interpreter, path: &str) -> Result<(), Error> {
+    let contents = ids_json(repo, interpreter.id_to_oid_lookup())?;
 
-fn run(bare: bool, repo_path: &str, commands: &[Command]) -> Result<(), Error> {
-    let repo = if bare {
-        Repository::init_bare(repo_path)?
+    let result = if path == "-" {
+        io::stdout().write_all(contents.as_bytes())
     } else {
-        Repository::init(repo_path)?
+        std::fs::write(path, contents)
     };
 
+    result.map_err(|e| Error::Io(path.to_string(), e))
+}
+
+struct Options<'a> {
+    bare: bool,
+    // None: write a fast-import stream to standard output instead
+    repo_path: Option<&'a str>,
+    date: Option<&'a str>,
+    date_increment: Option<i64>,
+    emit_ids_path: Option<&'a str>,
+    graph: Option<GraphFormat>,
+}
+
+// Commands are interpreted one at a time, so that they can be streamed.
+fn interpret<B, I>(interpreter: &mut Interpreter<B>, options: &Options, commands: I) -> Result<(), Error>
+  where B: Backend,
+        I: IntoIterator<Item = Result<Command, Error>> {
+    if let Some(date_increment) = options.date_increment {
+        interpreter.set_date_increment(date_increment);
+    }
+    if let Some(date) = options.date {
+        interpreter.set_date(parse_date(date)?);
+    }
+
+    for (index, command) in commands.into_iter().enumerate() {
+        let command = command?;
+        interpreter.interpret_command(&command).map_err(|e| e.in_command(index))?;
+    }
+
+    Ok(())
+}
+
+fn run<I>(options: &Options, commands: I) -> Result<(), Error>
+  where I: IntoIterator<Item = Result<Command, Error>> {
+    let repo_path = match options.repo_path {
+        Some(repo_path) => repo_path,
+        None => {
+            // The trees are computed in a scratch repository
+            let scratch = TemporaryRepository::new()?;
+            let stdout = io::stdout();
+            let mut interpreter = Interpreter::with_backend(scratch.repository(), FastImportBackend::new(stdout.lock()))?;
+            interpret(&mut interpreter, options, commands)?;
+            interpreter.finish()?;
+            return Ok(());
+        }
+    };
+
+    let repo = init_repository(repo_path, options.bare)?;
+
     let mut interpreter = Interpreter::new(&repo)?;
+    interpret(&mut interpreter, options, commands)?;
 
-    for command in commands.iter() {
-        interpreter.interpret_command(command)?;
+    if let Some(emit_ids_path) = options.emit_ids_path {
+        emit_ids(&repo, &interpreter, emit_ids_path)?;
     }
 
+    if let Some(graph) = options.graph {
+        print!("{}", render_graph(&repo, interpreter.id_to_oid_lookup(), graph)?);
+    }
+
+    interpreter.finish()?;
+
     Ok(())
 }
 
+fn input_name(input: Option<&str>) -> String {
+    input.unwrap_or("<stdin>").to_string()
+}
+
+fn open_input(input: Option<&str>) -> Result<Box<dyn Read>, Error> {
+    if let Some(input) = input {
+        // Read from a file
+        let file = File::open(input).map_err(|e| Error::Io(input.to_string(), e))?;
+        Ok(Box::new(BufReader::new(file)))
+    } else {
+        // Read from stdin
+        Ok(Box::new(BufReader::new(io::stdin())))
+    }
+}
+
+fn read_commands(input: Option<&str>, format: Format) -> Result<Vec<Command>, Error> {
+    let mut contents = Vec::new();
+    open_input(input)?.read_to_end(&mut contents).map_err(|e| Error::Io(input_name(input), e))?;
+
+    // fast-export streams can contain binary files
+    if format == Format::FastExport {
+        return parse_fast_export(&contents);
+    }
+
+    let contents = String::from_utf8(contents)
+        .map_err(|e| Error::Io(input_name(input), io::Error::new(io::ErrorKind::InvalidData, e)))?;
+    parse_commands(&contents, format)
+}
+
+// An explicit --format wins. Otherwise, it's guessed from the input file's extension, or JSON.
+fn input_format(format: Option<&str>, input: Option<&str>) -> Result<Format, Error> {
+    if let Some(format) = format {
+        return format.parse();
+    }
+
+    Ok(input.and_then(Format::from_path).unwrap_or(Format::Json))
+}
+
+// Reads a sequence of JSON values, one command each, as they arrive.
+// Works for newline-delimited JSON, and for values that are concatenated without newlines.
+fn stream_commands(input: Option<&str>) -> Result<impl Iterator<Item = Result<Command, Error>>, Error> {
+    let reader = open_input(input)?;
+    let stream = serde_json::Deserializer::from_reader(reader).into_iter::<Command>();
+
+    Ok(stream.map(|command| command.map_err(Error::from)))
+}
+
+// Writes the commands that reproduce an existing repository, as JSON
+fn export(matches: &ArgMatches) -> Result<(), Error> {
+    let repo_path = matches.value_of("REPO").unwrap();
+    let output = matches.value_of("output").unwrap_or("-");
+
+    let depth: Option<usize> = if matches.is_present("depth") {
+        Some(value_t!(matches, "depth", usize).unwrap_or_else(|e| e.exit()))
+    } else {
+        None
+    };
+
+    let options = ExportOptions {
+        drop_contents: matches.is_present("no-contents"),
+        anonymize: matches.is_present("anonymize"),
+        depth,
+    };
+
+    let repo = Repository::open(repo_path)?;
+    let commands = export_repository(&repo, &options)?;
+    let contents = format!("{}\n", serde_json::to_string_pretty(&commands)?);
+
+    let result = if output == "-" {
+        io::stdout().write_all(contents.as_bytes())
+    } else {
+        std::fs::write(output, contents)
+    };
+
+    result.map_err(|e| Error::Io(output.to_string(), e))
+}
+
+// Prints every mismatch between the repository and the spec. Returns whether they matched.
+fn verify(matches: &ArgMatches) -> Result<bool, Error> {
+    let repo_path = matches.value_of("REPO").unwrap();
+    let input: Option<&str> = matches.value_of("input");
+
+    let format = input_format(matches.value_of("format"), input)?;
+    let commands = read_commands(input, format)?;
+
+    let repo = Repository::open(repo_path)?;
+    let mismatches = verify_repository(&repo, &commands)?;
+
+    for mismatch in &mismatches {
+        println!("{}", mismatch);
+    }
+
+    Ok(mismatches.is_empty())
+}
+
+fn print_error(e: &Error) {
+    use colored::*;
+
+    eprintln!("{} {}", "error:".color("red"), e);
+}
+
+fn exit_with_error(e: Error) -> ! {
+    print_error(&e);
+    std::process::exit(1);
+}
+
+// Prints every problem that can be found without writing anything. Returns false if there are any.
+fn check(commands: &[Command]) -> bool {
+    let errors = check_commands(commands);
+    for e in &errors {
+        print_error(e);
+    }
+
+    errors.is_empty()
+}
+
 fn main() {
     let matches = App::new("Generate Git repo")
         .version(crate_version!())
         .about("Generates a Git repo (duh). Project: https://github.com/nukep/generate-git-repo/")
+        .setting(AppSettings::SubcommandsNegateReqs)
 
-        // .arg(Arg::with_name("json-stream")
-        //     .long("json-stream")
-        //     .help("Reads the commands as streaming JSON values, as they arrive. Doesn't require a surrounding array."))
+        .subcommand(SubCommand::with_name("export")
+            .about("Prints the commands that reproduce an existing repository's commits, branches and tags.")
+            .arg(Arg::with_name("output")
+                .long("output")
+                .short("o")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Writes the commands to FILE instead of standard output."))
+            .arg(Arg::with_name("no-contents")
+                .long("no-contents")
+                .help("Replaces the contents of every file with an empty string."))
+            .arg(Arg::with_name("anonymize")
+                .long("anonymize")
+                .help("Replaces author, committer and tagger names and emails."))
+            .arg(Arg::with_name("depth")
+                .long("depth")
+                .takes_value(true)
+                .value_name("N")
+                .help("Only exports commits that are at most N commits away from a branch or tag."))
+            .arg(Arg::with_name("REPO")
+                .help("The path of the Git repository to export.")
+                .required(true)))
+
+        .subcommand(SubCommand::with_name("verify")
+            .about("Checks that an existing repository matches the commands. Prints the differences.")
+            .arg(Arg::with_name("input")
+                .long("input")
+                .short("i")
+                .takes_value(true)
+                .help("Uses the provided file instead of standard input."))
+            .arg(Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["json", "yaml", "toml", "dsl", "fast-export"])
+                .help("The input format. Defaults to the --input file extension, or json."))
+            .arg(Arg::with_name("REPO")
+                .help("The path of the Git repository to check.")
+                .required(true)))
+
+        .arg(Arg::with_name("json-stream")
+            .long("json-stream")
+            .help("Reads the commands as streaming JSON values, as they arrive. Doesn't require a surrounding array. The input isn't validated before it's written, unless --check is given."))
 
         .arg(Arg::with_name("input")
             .long("input")
@@ -45,43 +283,168 @@ fn main() {
             .takes_value(true)
             .help("Uses the provided file instead of standard input."))
 
+        .arg(Arg::with_name("format")
+            .long("format")
+            .takes_value(true)
+            .possible_values(&["json", "yaml", "toml", "dsl", "fast-export"])
+            .conflicts_with("json-stream")
+            .help("The input format. Defaults to the --input file extension, or json."))
+
         .arg(Arg::with_name("bare")
             .long("bare")
             .help("Initializes a bare Git repository."))
 
+        .arg(Arg::with_name("date")
+            .long("date")
+            .takes_value(true)
+            .value_name("DATE")
+            .help("Uses deterministic dates, starting at DATE (e.g. \"1500000000 +0200\"). Uses the current time by default."))
+
+        .arg(Arg::with_name("date-increment")
+            .long("date-increment")
+            .takes_value(true)
+            .value_name("SECONDS")
+            .help("The number of seconds between each deterministic date. Defaults to 60."))
+
+        .arg(Arg::with_name("emit-ids")
+            .long("emit-ids")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Writes the Git commit hash of every commit id, and the created refs, as JSON. Use - for standard output."))
+
+        .arg(Arg::with_name("graph")
+            .long("graph")
+            .takes_value(true)
+            .possible_values(&["dot", "ascii", "mermaid"])
+            .help("Prints the generated commits as a graph, labeled with their ids, branches and tags."))
+
+        .arg(Arg::with_name("output")
+            .long("output")
+            .takes_value(true)
+            .possible_values(&["repo", "fast-import"])
+            .help("Writes to a Git repository (the default), or a `git fast-import` stream on standard output."))
+
+        .arg(Arg::with_name("check")
+            .long("check")
+            .help("Only validates the input, and reports every problem. Nothing is written."))
+
         .arg(Arg::with_name("REPO_PATH")
             .help("The path of the Git repository to write to. Creates it if it doesn't exist.")
-            .required(true))
+            .required_unless_one(&["output", "check"]))
 
         .get_matches();
 
+    if let Some(matches) = matches.subcommand_matches("export") {
+        if let Err(e) = export(matches) {
+            exit_with_error(e);
+        }
+        return;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("verify") {
+        match verify(matches) {
+            Ok(true) => return,
+            Ok(false) => std::process::exit(1),
+            Err(e) => exit_with_error(e)
+        }
+    }
+
     let bare = matches.is_present("bare");
+    let json_stream = matches.is_present("json-stream");
     
     let input: Option<&str> = matches.value_of("input");
+    let format: Option<&str> = matches.value_of("format");
 
-    let repo_path = matches.value_of("REPO_PATH").unwrap();
+    let date: Option<&str> = matches.value_of("date");
+    let date_increment: Option<i64> = if matches.is_present("date-increment") {
+        Some(value_t!(matches, "date-increment", i64).unwrap_or_else(|e| e.exit()))
+    } else {
+        None
+    };
 
-    let commands: Vec<Command> = if let Some(input) = input {
-        use std::fs::File;
-        use std::io::BufReader;
-        // Read from a file
-        let file = File::open(input).unwrap();
-        let mut buf_reader = BufReader::new(file);
-        let mut contents = String::new();
-        buf_reader.read_to_string(&mut contents).unwrap();
+    let emit_ids_path: Option<&str> = matches.value_of("emit-ids");
 
-        serde_json::from_str(&contents).unwrap()
-    } else {
-        // Read from stdin
+    let graph: Option<GraphFormat> = matches.value_of("graph").map(|graph| graph.parse().unwrap());
 
-        let mut contents = String::new();
-        io::stdin().read_to_string(&mut contents).unwrap();
+    let check_only = matches.is_present("check");
+    let fast_import = matches.value_of("output") == Some("fast-import");
+    let repo_path: Option<&str> = matches.value_of("REPO_PATH");
 
-        serde_json::from_str(&contents).unwrap()
-    };
+    if fast_import && repo_path.is_some() {
+        exit_with_error(Error::InvalidValue("REPO_PATH can't be used with --output fast-import".to_string()));
+    }
+    if fast_import && (bare || emit_ids_path.is_some() || graph.is_some()) {
+        exit_with_error(Error::InvalidValue("--bare, --emit-ids and --graph can't be used with --output fast-import".to_string()));
+    }
+    // Both would be written to standard output
+    if emit_ids_path == Some("-") && graph.is_some() {
+        exit_with_error(Error::InvalidValue("--emit-ids - can't be used with --graph. Write the ids to a file instead.".to_string()));
+    }
+    if !fast_import && !check_only && repo_path.is_none() {
+        exit_with_error(Error::InvalidValue("REPO_PATH is required".to_string()));
+    }
+
+    let options = Options { bare, repo_path, date, date_increment, emit_ids_path, graph };
+
+    // Streamed commands are only checked with --check, because they're written as they arrive
+    let result = if json_stream && !check_only {
+        stream_commands(input).and_then(|commands| {
+            run(&options, commands)
+        })
+    } else {
+        let commands = if json_stream {
+            stream_commands(input).and_then(|commands| commands.collect())
+        } else {
+            input_format(format, input).and_then(|format| read_commands(input, format))
+        };
 
-    match run(bare, repo_path, &commands) {
-        Ok(()) => {}
-        Err(e) => println!("error: {}", e)
+        commands.and_then(|commands| {
+            if !check(&commands) {
+                std::process::exit(1);
+            }
+            if check_only {
+                return Ok(());
+            }
+            run(&options, commands.into_iter().map(Ok))
+        })
     };
+
+    if let Err(e) = result {
+        exit_with_error(e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_json_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init_bare(dir.path()).unwrap();
+
+        // A ref that was already in the repository, and isn't reported
+        let existing = {
+            let signature = git2::Signature::now("Existing", "existing@example.com").unwrap();
+            let tree = repo.find_tree(repo.treebuilder(None).unwrap().write().unwrap()).unwrap();
+            repo.commit(Some("refs/heads/existing"), &signature, &signature, "Existing", &tree, &[]).unwrap()
+        };
+        repo.reference("refs/notes/other", existing, false, "").unwrap();
+
+        let commands: Vec<Command> = serde_json::from_str(r#"[
+            { "type": "commit", "id": "a", "branches": ["master"] },
+            { "type": "commit", "id": "b", "parents": ["a"], "tags": ["v1"] },
+            { "type": "tag", "name": "v2", "on": "b", "message": "Annotated" }
+        ]"#).unwrap();
+        let mut interpreter = Interpreter::new(&repo).unwrap();
+        interpreter.interpret_commands(&commands).unwrap();
+
+        let json: serde_json::Value = serde_json::from_str(&ids_json(&repo, interpreter.id_to_oid_lookup()).unwrap()).unwrap();
+        let oid = |id: &str| interpreter.id_to_oid_lookup()[id].to_string();
+
+        assert_eq!(json, serde_json::json!({
+            "ids": { "a": oid("a"), "b": oid("b") },
+            "refs": { "refs/heads/master": oid("a"), "refs/tags/v1": oid("b"), "refs/tags/v2": oid("b") }
+        }));
+    }
 }
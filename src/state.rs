@@ -0,0 +1,405 @@
+// Operations that are left in progress in a non-bare repository, like Git does when it stops for the user.
+
+use git2::{Repository, Oid, AnnotatedCommit, Commit, RepositoryState, StatusOptions};
+use git2::build::CheckoutBuilder;
+use std::fs;
+use std::path::Path;
+
+use super::error::Error;
+use super::interpreter::pick_trees;
+
+fn write_file(path: &Path, contents: &str) -> Result<(), Error> {
+    fs::write(path, contents).map_err(|e| Error::Io(path.display().to_string(), e))
+}
+
+fn write_git_file(repo: &Repository, name: &str, contents: &str) -> Result<(), Error> {
+    write_file(&repo.path().join(name), contents)
+}
+
+// Only one operation can be in progress at a time
+fn check_can_start(repo: &Repository, operation: &str) -> Result<(), Error> {
+    if repo.is_bare() {
+        return Err(Error::InvalidValue(format!("An in-progress {} needs a non-bare repository", operation)));
+    }
+    if repo.state() != RepositoryState::Clean {
+        return Err(Error::InvalidValue(format!("Can't start a {}, because another operation is in progress: {:?}", operation, repo.state())));
+    }
+    Ok(())
+}
+
+// Like Git, operations that check out another commit or rewrite the index don't start on top of
+// uncommitted changes (e.g. from a worktree command). Untracked and ignored files are fine.
+fn check_clean(repo: &Repository, operation: &str) -> Result<(), Error> {
+    let statuses = repo.statuses(Some(StatusOptions::new().include_untracked(false).include_ignored(false)))?;
+    if let Some(entry) = statuses.iter().next() {
+        return Err(Error::InvalidValue(format!("Can't start a {}, because of uncommitted changes: {}",
+                                               operation, entry.path().unwrap_or(""))));
+    }
+    Ok(())
+}
+
+fn detach_head(repo: &Repository, oid: Oid) -> Result<(), Error> {
+    let commit = repo.find_commit(oid)?;
+    repo.checkout_tree(commit.as_object(), Some(CheckoutBuilder::new().safe()))?;
+    repo.set_head_detached(oid)?;
+    Ok(())
+}
+
+// e.g. "1a2b3c4 (Add README)", like Git's conflict markers and messages
+fn short_description(commit: &Commit) -> String {
+    format!("{} ({})", &commit.id().to_string()[..7], commit.summary().unwrap_or(""))
+}
+
+// Git's default message for a revert
+pub fn revert_message(commit: &Commit) -> String {
+    format!("Revert \"{}\"\n\nThis reverts commit {}.\n", commit.summary().unwrap_or(""), commit.id())
+}
+
+// Merges the other commits into HEAD, which must be at the first commit. Conflicts are left in the index
+// (at stages 1, 2 and 3) and in the work tree (with conflict markers), and MERGE_HEAD and MERGE_MSG are written.
+pub fn start_merge(repo: &Repository, commit_oids: &[Oid], message: Option<&str>) -> Result<(), Error> {
+    check_can_start(repo, "merge")?;
+    if commit_oids.len() < 2 {
+        return Err(Error::InvalidValue("An in-progress merge needs at least two different commits".to_string()));
+    }
+
+    let head_oid = repo.head()?.peel_to_commit()?.id();
+    if head_oid != commit_oids[0] {
+        return Err(Error::InvalidValue(format!("HEAD must be at the first commit of an in-progress merge, but it's at {}", head_oid)));
+    }
+
+    let their_commits: Vec<AnnotatedCommit> = commit_oids[1..].iter()
+        .map(|oid| repo.find_annotated_commit(*oid))
+        .collect::<Result<_, _>>()?;
+    let their_commit_refs: Vec<&AnnotatedCommit> = their_commits.iter().collect();
+
+    repo.merge(&their_commit_refs, None, None)?;
+
+    if let Some(message) = message {
+        write_git_file(repo, "MERGE_MSG", &format!("{}\n", message.trim_end()))?;
+    }
+
+    Ok(())
+}
+
+pub struct Rebase<'a> {
+    // .git/rebase-apply instead of .git/rebase-merge
+    pub apply: bool,
+    pub interactive: bool,
+
+    pub onto: Oid,
+    pub orig_head: Oid,
+    pub head: Oid,
+    pub branch: Option<&'a str>,
+
+    pub done: Vec<Oid>,
+    pub todo: Vec<Oid>,
+}
+
+// e.g. "pick 1a2b3c4d... Add README\n" for each commit
+fn todo_list(repo: &Repository, oids: &[Oid]) -> Result<String, Error> {
+    let mut list = String::new();
+    for oid in oids {
+        let commit = repo.find_commit(*oid)?;
+        list.push_str(&format!("pick {} {}\n", oid, commit.summary().unwrap_or("")));
+    }
+    Ok(list)
+}
+
+// Writes the rebase's state directory, and detaches HEAD at the rebase's current commit
+pub fn start_rebase(repo: &Repository, rebase: &Rebase) -> Result<(), Error> {
+    check_can_start(repo, "rebase")?;
+    check_clean(repo, "rebase")?;
+
+    let dir = repo.path().join(if rebase.apply { "rebase-apply" } else { "rebase-merge" });
+    fs::create_dir_all(&dir).map_err(|e| Error::Io(dir.display().to_string(), e))?;
+
+    let head_name = match rebase.branch {
+        Some(branch) => format!("refs/heads/{}", branch),
+        None => "detached HEAD".to_string()
+    };
+    write_file(&dir.join("head-name"), &format!("{}\n", head_name))?;
+    write_file(&dir.join("onto"), &format!("{}\n", rebase.onto))?;
+    write_file(&dir.join("orig-head"), &format!("{}\n", rebase.orig_head))?;
+
+    let total = rebase.done.len() + rebase.todo.len();
+    if rebase.apply {
+        write_file(&dir.join("rebasing"), "")?;
+        write_file(&dir.join("next"), &format!("{}\n", rebase.done.len() + 1))?;
+        write_file(&dir.join("last"), &format!("{}\n", total))?;
+    } else {
+        if rebase.interactive {
+            write_file(&dir.join("interactive"), "")?;
+        }
+        write_file(&dir.join("done"), &todo_list(repo, &rebase.done)?)?;
+        write_file(&dir.join("git-rebase-todo"), &todo_list(repo, &rebase.todo)?)?;
+        write_file(&dir.join("msgnum"), &format!("{}\n", rebase.done.len()))?;
+        write_file(&dir.join("end"), &format!("{}\n", total))?;
+    }
+
+    write_git_file(repo, "ORIG_HEAD", &format!("{}\n", rebase.orig_head))?;
+    detach_head(repo, rebase.head)
+}
+
+// Applies the commit's changes to HEAD (or reverses them), like `git cherry-pick` and `git revert`.
+// Conflicts are left in the index and the work tree, and CHERRY_PICK_HEAD (or REVERT_HEAD) and MERGE_MSG are written.
+pub fn start_pick(repo: &Repository, commit_oid: Oid, revert: bool) -> Result<(), Error> {
+    let operation = if revert { "revert" } else { "cherry-pick" };
+    check_can_start(repo, operation)?;
+    check_clean(repo, operation)?;
+
+    let head_commit = repo.head()?.peel_to_commit()?;
+    let commit = repo.find_commit(commit_oid)?;
+    let (ancestor_tree, their_tree) = pick_trees(repo, &commit, revert)?;
+    let their_label = if revert {
+        format!("parent of {}", short_description(&commit))
+    } else {
+        short_description(&commit)
+    };
+
+    let mut merged = repo.merge_trees(&ancestor_tree, &head_commit.tree()?, &their_tree, None)?;
+    repo.checkout_index(Some(&mut merged), Some(CheckoutBuilder::new()
+        .safe()
+        .allow_conflicts(true)
+        .our_label("HEAD")
+        .their_label(&their_label)))?;
+
+    // The merged index only exists in memory
+    let mut index = repo.index()?;
+    index.clear()?;
+    for entry in merged.iter() {
+        index.add(&entry)?;
+    }
+    index.write()?;
+
+    let message = if revert {
+        revert_message(&commit)
+    } else {
+        format!("{}\n", commit.message().unwrap_or("").trim_end())
+    };
+    write_git_file(repo, if revert { "REVERT_HEAD" } else { "CHERRY_PICK_HEAD" }, &format!("{}\n", commit_oid))?;
+    write_git_file(repo, "MERGE_MSG", &message)
+}
+
+// Marks the commits like `git bisect bad`, `good` and `skip` do, and optionally detaches HEAD at the commit being tested
+pub fn start_bisect(repo: &Repository, bad: Oid, good: &[Oid], skip: &[Oid], head: Option<Oid>) -> Result<(), Error> {
+    check_can_start(repo, "bisect")?;
+    if head.is_some() {
+        check_clean(repo, "bisect")?;
+    }
+
+    // `git bisect reset` goes back to this branch (or commit)
+    let head_ref = repo.find_reference("HEAD")?;
+    let start = match head_ref.symbolic_target() {
+        Some(target) => target.trim_start_matches("refs/heads/").to_string(),
+        None => repo.head()?.peel_to_commit()?.id().to_string()
+    };
+
+    let mut log = "git bisect start\n".to_string();
+    let marks = std::iter::once(("bad", bad))
+        .chain(good.iter().map(|oid| ("good", *oid)))
+        .chain(skip.iter().map(|oid| ("skip", *oid)));
+    for (term, oid) in marks {
+        let commit = repo.find_commit(oid)?;
+        let ref_name = if term == "bad" { "refs/bisect/bad".to_string() } else { format!("refs/bisect/{}-{}", term, oid) };
+        repo.reference(&ref_name, oid, true, &format!("bisect {}", term))?;
+
+        log.push_str(&format!("# {}: [{}] {}\n", term, oid, commit.summary().unwrap_or("")));
+        log.push_str(&format!("git bisect {} {}\n", term, oid));
+    }
+
+    write_git_file(repo, "BISECT_START", &format!("{}\n", start))?;
+    write_git_file(repo, "BISECT_TERMS", "bad\ngood\n")?;
+    write_git_file(repo, "BISECT_NAMES", "\n")?;
+    write_git_file(repo, "BISECT_LOG", &log)?;
+
+    if let Some(head) = head {
+        write_git_file(repo, "BISECT_EXPECTED_REV", &format!("{}\n", head))?;
+        detach_head(repo, head)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::builder::RepoBuilder;
+    use super::super::test_fixtures::{with_command, read_git_file};
+    use git2::RepositoryState;
+    use std::path::Path;
+
+    #[test]
+    fn merge_conflict_test() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let generated = RepoBuilder::new(dir.path())
+            .commit("a").file("README", "Hello\n").file("other.txt", "Other\n")
+            .commit("b").parents(["a"]).file("README", "Hello B\n").file("other.txt", "Other\n").branch("master")
+            .commit("c").parents(["a"]).file("README", "Hello C\n").file("other.txt", "Other C\n").branch("feature")
+            .merge("d", ["b", "c"]).message("Merge feature").in_progress()
+            .build()
+            .unwrap();
+
+        let repo = generated.repository();
+        assert_eq!(repo.state(), RepositoryState::Merge);
+
+        let merge_head = std::fs::read_to_string(repo.path().join("MERGE_HEAD")).unwrap();
+        assert_eq!(merge_head.trim(), generated.oid("c").unwrap().to_string());
+        assert_eq!(std::fs::read_to_string(repo.path().join("MERGE_MSG")).unwrap(), "Merge feature\n");
+
+        // The conflicting file has an ancestor, "ours" and "theirs" entry
+        let index = repo.index().unwrap();
+        let stages: Vec<u16> = index.iter()
+            .filter(|entry| entry.path == b"README")
+            .map(|entry| (entry.flags >> 12) & 3)
+            .collect();
+        assert_eq!(stages, vec![1, 2, 3]);
+
+        let readme = std::fs::read_to_string(repo.workdir().unwrap().join("README")).unwrap();
+        assert!(readme.starts_with("<<<<<<< HEAD\nHello B\n=======\nHello C\n>>>>>>> "), "{}", readme);
+
+        // The other file merged cleanly, and is staged
+        assert_eq!(std::fs::read_to_string(repo.workdir().unwrap().join("other.txt")).unwrap(), "Other C\n");
+        assert_eq!(generated.oid("d"), None);
+    }
+
+    #[test]
+    fn merge_head_error_test() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // HEAD (master) is at "c", not "b"
+        let result = RepoBuilder::new(dir.path())
+            .commit("a")
+            .commit("b").parents(["a"]).file("README", "B")
+            .commit("c").parents(["a"]).file("README", "C").branch("master")
+            .merge("d", ["b", "c"]).in_progress()
+            .build();
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn rebase_test() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let generated = with_command(&dir.path().join("merge"), r#"{
+            "type": "state", "state": "rebase-merge", "interactive": true,
+            "onto": "m", "orig_head": "c", "branch": "feature", "done": ["b"], "todo": ["c"]
+        }"#).unwrap();
+        let repo = generated.repository();
+        let oid = |id: &str| generated.oid(id).unwrap().to_string();
+
+        assert_eq!(repo.state(), RepositoryState::RebaseInteractive);
+        assert_eq!(read_git_file(&generated, "rebase-merge/head-name"), "refs/heads/feature\n");
+        assert_eq!(read_git_file(&generated, "rebase-merge/onto"), format!("{}\n", oid("m")));
+        assert_eq!(read_git_file(&generated, "rebase-merge/orig-head"), format!("{}\n", oid("c")));
+        assert_eq!(read_git_file(&generated, "rebase-merge/done"), format!("pick {} Change one\n", oid("b")));
+        assert_eq!(read_git_file(&generated, "rebase-merge/git-rebase-todo"), format!("pick {} Change one again\n", oid("c")));
+        assert_eq!(read_git_file(&generated, "rebase-merge/msgnum"), "1\n");
+        assert_eq!(read_git_file(&generated, "rebase-merge/end"), "2\n");
+
+        // HEAD is detached at "onto", and checked out
+        assert!(repo.head_detached().unwrap());
+        assert_eq!(repo.head().unwrap().target().unwrap().to_string(), oid("m"));
+        assert_eq!(std::fs::read_to_string(repo.workdir().unwrap().join("README")).unwrap(), "one\ntwo\nTHREE\n");
+
+        let generated = with_command(&dir.path().join("apply"), r#"{
+            "type": "state", "state": "rebase-apply", "onto": "m", "orig_head": "c", "head": "a", "todo": ["b", "c"]
+        }"#).unwrap();
+        let repo = generated.repository();
+
+        assert_eq!(repo.state(), RepositoryState::Rebase);
+        assert_eq!(read_git_file(&generated, "rebase-apply/head-name"), "detached HEAD\n");
+        assert_eq!(read_git_file(&generated, "rebase-apply/next"), "1\n");
+        assert_eq!(read_git_file(&generated, "rebase-apply/last"), "2\n");
+        assert_eq!(repo.head().unwrap().target(), generated.oid("a"));
+    }
+
+    #[test]
+    fn cherry_pick_test() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // "c" changes the first line of README, which "m" doesn't have yet
+        let generated = with_command(dir.path(), r#"{ "type": "state", "state": "cherry-pick", "commit": "c" }"#).unwrap();
+        let repo = generated.repository();
+        let c = generated.oid("c").unwrap().to_string();
+
+        assert_eq!(repo.state(), RepositoryState::CherryPick);
+        assert_eq!(read_git_file(&generated, "CHERRY_PICK_HEAD"), format!("{}\n", c));
+        assert_eq!(read_git_file(&generated, "MERGE_MSG"), "Change one again\n");
+
+        let index = repo.index().unwrap();
+        let stages: Vec<u16> = index.iter()
+            .filter(|entry| entry.path == b"README")
+            .map(|entry| (entry.flags >> 12) & 3)
+            .collect();
+        assert_eq!(stages, vec![1, 2, 3]);
+
+        let workdir = repo.workdir().unwrap();
+        let readme = std::fs::read_to_string(workdir.join("README")).unwrap();
+        assert_eq!(readme, format!("<<<<<<< HEAD\none\n=======\n1\n>>>>>>> {} (Change one again)\ntwo\nTHREE\n", &c[..7]));
+
+        // The file that was added cleanly is staged
+        assert_eq!(std::fs::read_to_string(workdir.join("c.txt")).unwrap(), "C\n");
+        assert!(index.get_path(Path::new("c.txt"), 0).is_some());
+        assert!(!workdir.join("b.txt").exists());
+    }
+
+    #[test]
+    fn revert_test() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let generated = RepoBuilder::new(dir.path())
+            .commit("a").message("Add README").file("README", "Hello\n")
+            .commit("b").parents(["a"]).message("Add b.txt").file("README", "Hello\n").file("b.txt", "B\n").branch("master")
+            .finish()
+            .command(serde_json::from_str(r#"{ "type": "state", "state": "revert", "commit": "b" }"#).unwrap())
+            .build()
+            .unwrap();
+        let repo = generated.repository();
+        let b = generated.oid("b").unwrap().to_string();
+
+        assert_eq!(repo.state(), RepositoryState::Revert);
+        assert_eq!(read_git_file(&generated, "REVERT_HEAD"), format!("{}\n", b));
+        assert_eq!(read_git_file(&generated, "MERGE_MSG"), format!("Revert \"Add b.txt\"\n\nThis reverts commit {}.\n", b));
+
+        // The reverted file is deleted from the index and the work tree
+        assert!(repo.index().unwrap().get_path(Path::new("b.txt"), 0).is_none());
+        assert!(!repo.workdir().unwrap().join("b.txt").exists());
+    }
+
+    #[test]
+    fn bisect_test() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let generated = with_command(dir.path(), r#"{ "type": "state", "state": "bisect", "bad": "c", "good": ["a"], "skip": ["m"], "head": "b" }"#).unwrap();
+        let repo = generated.repository();
+        let oid = |id: &str| generated.oid(id).unwrap().to_string();
+
+        assert_eq!(repo.state(), RepositoryState::Bisect);
+        assert_eq!(read_git_file(&generated, "BISECT_START"), "master\n");
+        assert_eq!(read_git_file(&generated, "BISECT_LOG"), format!(
+            "git bisect start\n# bad: [{c}] Change one again\ngit bisect bad {c}\n# good: [{a}] Add README\ngit bisect good {a}\n\
+             # skip: [{m}] Change three\ngit bisect skip {m}\n",
+            a = oid("a"), c = oid("c"), m = oid("m")));
+
+        assert_eq!(repo.refname_to_id("refs/bisect/bad").unwrap(), generated.oid("c").unwrap());
+        assert!(repo.find_reference(&format!("refs/bisect/good-{}", oid("a"))).is_ok());
+        assert!(repo.find_reference(&format!("refs/bisect/skip-{}", oid("m"))).is_ok());
+        assert_eq!(repo.head().unwrap().target(), generated.oid("b"));
+    }
+
+    #[test]
+    fn one_operation_at_a_time_test() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let result = RepoBuilder::new(dir.path())
+            .commit("a").branch("master")
+            .finish()
+            .command(serde_json::from_str(r#"{ "type": "state", "state": "bisect", "bad": "a" }"#).unwrap())
+            .command(serde_json::from_str(r#"{ "type": "state", "state": "revert", "commit": "a" }"#).unwrap())
+            .build();
+
+        assert!(result.is_err());
+    }
+}
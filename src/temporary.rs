@@ -0,0 +1,37 @@
+use git2::Repository;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::error::Error;
+
+// Makes the temporary repository paths unique within a process
+static TEMPORARY_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// A bare repository in the system's temporary directory, used as scratch space.
+// It's removed when dropped.
+pub struct TemporaryRepository {
+    repo: Option<Repository>,
+    path: PathBuf,
+}
+
+impl TemporaryRepository {
+    pub fn new() -> Result<TemporaryRepository, Error> {
+        let name = format!("generate-git-repo-{}-{}", std::process::id(), TEMPORARY_COUNTER.fetch_add(1, Ordering::SeqCst));
+        let path = std::env::temp_dir().join(name);
+        let repo = Repository::init_bare(&path)?;
+
+        Ok(TemporaryRepository { repo: Some(repo), path })
+    }
+
+    pub fn repository(&self) -> &Repository {
+        self.repo.as_ref().unwrap()
+    }
+}
+
+impl Drop for TemporaryRepository {
+    fn drop(&mut self) {
+        // Close the repository before its files are removed
+        self.repo.take();
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
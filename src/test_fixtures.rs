@@ -0,0 +1,38 @@
+// Fixtures that are shared by the tests of several modules
+
+use super::builder::{RepoBuilder, GeneratedRepo};
+use super::command::Command;
+use super::error::Error;
+use std::path::Path;
+
+// master: a - m, feature: a - b - c. "b" and "c" change the first line of README, and "m" changes the last one,
+// so "b" can be picked onto "m" cleanly, but "c" conflicts.
+fn pick_fixture(path: &Path) -> RepoBuilder {
+    RepoBuilder::new(path)
+        .commit("a").message("Add README").file("README", "one\ntwo\nthree\n")
+        .commit("b").parents(["a"]).message("Change one").author("Original", "original@example.com").author_date("1500000000 +0200")
+            .file("README", "ONE\ntwo\nthree\n").file("b.txt", "B\n")
+        .commit("c").parents(["b"]).message("Change one again").file("README", "1\ntwo\nthree\n").file("b.txt", "B\n").file("c.txt", "C\n")
+            .branch("feature")
+        .commit("m").parents(["a"]).message("Change three").file("README", "one\ntwo\nTHREE\n").branch("master")
+        .finish()
+}
+
+// The fixture, followed by one more command
+pub fn with_command(path: &Path, command: &str) -> Result<GeneratedRepo, Error> {
+    let command: Command = serde_json::from_str(command).unwrap();
+    pick_fixture(path).command(command).build()
+}
+
+// A file in the .git directory
+pub fn read_git_file(generated: &GeneratedRepo, name: &str) -> String {
+    std::fs::read_to_string(generated.repository().path().join(name)).unwrap()
+}
+
+// A file in a commit's tree
+pub fn file_at(generated: &GeneratedRepo, id: &str, path: &str) -> Option<String> {
+    let repo = generated.repository();
+    let tree = repo.find_commit(generated.oid(id).unwrap()).unwrap().tree().unwrap();
+    let entry = tree.get_path(Path::new(path)).ok()?;
+    Some(String::from_utf8(repo.find_blob(entry.id()).unwrap().content().to_vec()).unwrap())
+}
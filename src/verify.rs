@@ -0,0 +1,368 @@
+// Checks an existing repository against a list of commands, as if they were a spec.
+//
+// The commands are interpreted into a temporary repository. Then, starting at every branch and tag,
+// the commits of both repositories are walked side by side, and their messages, trees and parents
+// are compared. Only commits reachable from a branch or tag in the spec are checked.
+
+use git2::{Repository, Oid, Tree, ObjectType};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+
+use super::command::Command;
+use super::error::Error;
+use super::interpreter::{Interpreter, FlatTree, flatten_tree};
+use super::temporary::TemporaryRepository;
+
+#[derive(Debug)]
+pub enum TreeChange {
+    Missing(String),
+    Unexpected(String),
+    Mode { path: String, expected: i32, actual: i32 },
+    // The contents are only included for short text files
+    Contents { path: String, expected: Option<String>, actual: Option<String> },
+}
+
+#[derive(Debug)]
+pub enum Mismatch {
+    // e.g. "refs/heads/master"
+    MissingRef(String),
+    UnexpectedRef(String),
+    Message { id: String, expected: String, actual: String },
+    ParentCount { id: String, expected: usize, actual: usize },
+    Tree { id: String, changes: Vec<TreeChange> },
+    // The same id was reached through two paths, which ended up at different commits
+    Ambiguous { id: String, first: Oid, second: Oid },
+}
+
+impl fmt::Display for TreeChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TreeChange::Missing(path) => write!(f, "- {} (missing)", path),
+            TreeChange::Unexpected(path) => write!(f, "+ {} (unexpected)", path),
+            TreeChange::Mode { path, expected, actual } => write!(f, "~ {} (expected mode {:o}, found {:o})", path, expected, actual),
+            TreeChange::Contents { path, expected, actual } => {
+                write!(f, "~ {} (contents differ)", path)?;
+                if let (Some(expected), Some(actual)) = (expected, actual) {
+                    write!(f, "\n    expected: {:?}\n    found:    {:?}", expected, actual)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mismatch::MissingRef(name) => write!(f, "{}: missing", name),
+            Mismatch::UnexpectedRef(name) => write!(f, "{}: unexpected", name),
+            Mismatch::Message { id, expected, actual } => {
+                write!(f, "commit {}: message differs\n  expected: {:?}\n  found:    {:?}", id, expected, actual)
+            },
+            Mismatch::ParentCount { id, expected, actual } => {
+                write!(f, "commit {}: expected {} parent(s), found {}", id, expected, actual)
+            },
+            Mismatch::Tree { id, changes } => {
+                write!(f, "commit {}: tree differs", id)?;
+                for change in changes {
+                    write!(f, "\n  {}", change)?;
+                }
+                Ok(())
+            },
+            Mismatch::Ambiguous { id, first, second } => {
+                write!(f, "commit {}: matches two different commits, {} and {}", id, first, second)
+            },
+        }
+    }
+}
+
+// What the spec says about a commit id. Unspecified messages and trees aren't checked.
+#[derive(Default)]
+struct Expectation {
+    message: bool,
+    tree: bool,
+}
+
+fn expectations(commands: &[Command]) -> HashMap<&str, Expectation> {
+    let mut expectations = HashMap::new();
+
+    for command in commands {
+        match command {
+            Command::Commit { id, message, tree, delete, .. } => {
+                expectations.insert(id.as_str(), Expectation { message: message.is_some(), tree: tree.is_some() || !delete.is_empty() });
+            },
+            Command::Merge { id, message, tree, delete, strategy, .. } => {
+                let tree = tree.is_some() || !delete.is_empty() || strategy.is_some();
+                expectations.insert(id.as_str(), Expectation { message: message.is_some(), tree });
+            },
+            // The tree is computed from the other commits
+            Command::CherryPick { id, message, .. } | Command::Revert { id, message, .. } => {
+                expectations.insert(id.as_str(), Expectation { message: message.is_some(), tree: true });
+            },
+            _ => ()
+        }
+    }
+
+    expectations
+}
+
+// Short text files are shown in full, so that small differences are easy to spot
+fn short_text(repo: &Repository, oid: Oid) -> Option<String> {
+    const MAX_LENGTH: usize = 200;
+
+    let blob = repo.find_blob(oid).ok()?;
+    let text = std::str::from_utf8(blob.content()).ok()?;
+    if text.len() <= MAX_LENGTH { Some(text.to_string()) } else { None }
+}
+
+fn diff_trees(expected_repo: &Repository, expected: &Tree, actual_repo: &Repository, actual: &Tree) -> Result<Vec<TreeChange>, Error> {
+    let mut expected_files = FlatTree::new();
+    let mut actual_files = FlatTree::new();
+    flatten_tree(expected_repo, expected, "", &mut expected_files)?;
+    flatten_tree(actual_repo, actual, "", &mut actual_files)?;
+
+    let mut changes = Vec::new();
+
+    for (path, (expected_mode, expected_oid)) in &expected_files {
+        match actual_files.get(path) {
+            None => changes.push(TreeChange::Missing(path.clone())),
+            Some((actual_mode, actual_oid)) => {
+                if expected_oid != actual_oid {
+                    changes.push(TreeChange::Contents {
+                        path: path.clone(),
+                        expected: short_text(expected_repo, *expected_oid),
+                        actual: short_text(actual_repo, *actual_oid),
+                    });
+                } else if expected_mode != actual_mode {
+                    changes.push(TreeChange::Mode { path: path.clone(), expected: *expected_mode, actual: *actual_mode });
+                }
+            }
+        }
+    }
+
+    for path in actual_files.keys() {
+        if !expected_files.contains_key(path) {
+            changes.push(TreeChange::Unexpected(path.clone()));
+        }
+    }
+
+    Ok(changes)
+}
+
+// Branches and tags, peeled to their commits
+fn commit_refs(repo: &Repository) -> Result<BTreeMap<String, Oid>, Error> {
+    let mut refs = BTreeMap::new();
+
+    for reference in repo.references()? {
+        let reference = reference?;
+        let name = match reference.name() {
+            Some(name) if name.starts_with("refs/heads/") || name.starts_with("refs/tags/") => name.to_string(),
+            _ => continue
+        };
+        if let Ok(commit) = reference.peel(ObjectType::Commit) {
+            refs.insert(name, commit.id());
+        }
+    }
+
+    Ok(refs)
+}
+
+fn compare(expected_repo: &Repository, id_to_oid_lookup: &HashMap<String, Oid>, actual_repo: &Repository,
+           commands: &[Command]) -> Result<Vec<Mismatch>, Error> {
+    let expectations = expectations(commands);
+
+    // A fast-forward merge shares its commit with another id
+    let mut oid_to_ids: HashMap<Oid, Vec<&str>> = HashMap::new();
+    for (id, oid) in id_to_oid_lookup {
+        oid_to_ids.entry(*oid).or_default().push(id);
+    }
+    for ids in oid_to_ids.values_mut() {
+        ids.sort();
+    }
+
+    let mut mismatches = Vec::new();
+
+    let expected_refs = commit_refs(expected_repo)?;
+    let actual_refs = commit_refs(actual_repo)?;
+
+    // (expected commit, actual commit)
+    let mut queue: VecDeque<(Oid, Oid)> = VecDeque::new();
+
+    for (name, expected_oid) in &expected_refs {
+        match actual_refs.get(name) {
+            Some(actual_oid) => queue.push_back((*expected_oid, *actual_oid)),
+            None => mismatches.push(Mismatch::MissingRef(name.clone()))
+        }
+    }
+    for name in actual_refs.keys() {
+        if !expected_refs.contains_key(name) {
+            mismatches.push(Mismatch::UnexpectedRef(name.clone()));
+        }
+    }
+
+    let mut matched: HashMap<Oid, Oid> = HashMap::new();
+    let mut ambiguous: HashSet<Oid> = HashSet::new();
+
+    while let Some((expected_oid, actual_oid)) = queue.pop_front() {
+        let ids = oid_to_ids.get(&expected_oid).cloned().unwrap_or_default();
+        let name = ids.first().map(|id| id.to_string()).unwrap_or_else(|| expected_oid.to_string());
+
+        if let Some(previous) = matched.get(&expected_oid) {
+            if *previous != actual_oid && ambiguous.insert(expected_oid) {
+                mismatches.push(Mismatch::Ambiguous { id: name, first: *previous, second: actual_oid });
+            }
+            continue;
+        }
+        matched.insert(expected_oid, actual_oid);
+
+        let expected = expected_repo.find_commit(expected_oid)?;
+        let actual = actual_repo.find_commit(actual_oid)?;
+
+        let check_message = ids.iter().any(|id| expectations.get(id).is_some_and(|e| e.message));
+        let check_tree = ids.iter().any(|id| expectations.get(id).is_some_and(|e| e.tree));
+
+        if check_message && expected.message_bytes() != actual.message_bytes() {
+            mismatches.push(Mismatch::Message {
+                id: name.clone(),
+                expected: String::from_utf8_lossy(expected.message_bytes()).into_owned(),
+                actual: String::from_utf8_lossy(actual.message_bytes()).into_owned(),
+            });
+        }
+
+        // Trees are content-addressed, so equal hashes mean equal trees
+        if check_tree && expected.tree_id() != actual.tree_id() {
+            let changes = diff_trees(expected_repo, &expected.tree()?, actual_repo, &actual.tree()?)?;
+            mismatches.push(Mismatch::Tree { id: name.clone(), changes });
+        }
+
+        if expected.parent_count() != actual.parent_count() {
+            mismatches.push(Mismatch::ParentCount { id: name, expected: expected.parent_count(), actual: actual.parent_count() });
+            continue;
+        }
+
+        // Parents are compared in order, e.g. the first parent of a merge is the branch that was merged into
+        for (expected_parent, actual_parent) in expected.parent_ids().zip(actual.parent_ids()) {
+            queue.push_back((expected_parent, actual_parent));
+        }
+    }
+
+    Ok(mismatches)
+}
+
+// The work tree, the index and in-progress operations don't change the commits or refs. They're skipped when the
+// spec is interpreted, because the expected repository is bare.
+fn changes_history(command: &Command) -> bool {
+    !matches!(command, Command::Worktree { .. } | Command::State(_) | Command::Merge { in_progress: true, .. })
+}
+
+// Returns the differences between the repository and the commands. An empty list means that it matches.
+pub fn verify_repository(repo: &Repository, commands: &[Command]) -> Result<Vec<Mismatch>, Error> {
+    let temporary = TemporaryRepository::new()?;
+    let expected_repo = temporary.repository();
+
+    let id_to_oid_lookup = {
+        let mut interpreter = Interpreter::new(expected_repo)?;
+        for (index, command) in commands.iter().enumerate().filter(|(_, command)| changes_history(command)) {
+            interpreter.interpret_command(command).map_err(|e| e.in_command(index))?;
+        }
+        interpreter.id_to_oid_lookup().clone()
+    };
+
+    compare(expected_repo, &id_to_oid_lookup, repo, commands)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::builder::RepoBuilder;
+    use super::super::dsl::parse_dsl;
+
+    fn verify(actual: &str, spec: &str) -> Vec<String> {
+        let dir = tempfile::tempdir().unwrap();
+        let builder = parse_dsl(actual).unwrap().into_iter()
+            .fold(RepoBuilder::new(dir.path()).bare(true), |builder, command| builder.command(command));
+        let generated = builder.build().unwrap();
+
+        let mismatches = verify_repository(generated.repository(), &parse_dsl(spec).unwrap()).unwrap();
+        mismatches.iter().map(|mismatch| mismatch.to_string()).collect()
+    }
+
+    const REPO: &str = r#"
+a: "Initial"
+  README: "Hello"
+b < a: "Commit B"
+  README: "Hello B"
+c < a: "Commit C"
+  README: "Hello"
+  c.txt: "C"
+d = merge b c: "Merge"
+  README: "Merged"
+  c.txt: "C"
+branch master -> d
+tag 1.0.0 -> a
+"#;
+
+    #[test]
+    fn verify_matching_test() {
+        assert!(verify(REPO, REPO).is_empty());
+
+        // Messages and trees that aren't specified aren't checked
+        let spec = "a\nb < a\nc < a\nd = merge b c\n  README: \"Merged\"\n  c.txt: \"C\"\nbranch master -> d\ntag 1.0.0 -> a\n";
+        assert!(verify(REPO, spec).is_empty());
+    }
+
+    #[test]
+    fn verify_mismatch_test() {
+        let spec = r#"
+a: "Initial"
+  README: "Hello"
+b < a: "Commit b"
+  README: "Hello B"
+c < a
+d = merge c b: "Merge"
+  README: "Merged!"
+  c.txt: "C"
+branch master -> d
+branch feature -> b
+"#;
+        let mismatches = verify(REPO, spec);
+        assert!(mismatches.contains(&"refs/heads/feature: missing".to_string()));
+        assert!(mismatches.contains(&"refs/tags/1.0.0: unexpected".to_string()));
+        assert!(mismatches.contains(&"commit d: tree differs\n  ~ README (contents differ)\n    expected: \"Merged!\"\n    found:    \"Merged\"".to_string()));
+        // The parents are swapped, so "c" is compared to "b", and "b" to "c"
+        assert!(mismatches.contains(&"commit b: message differs\n  expected: \"Commit b\"\n  found:    \"Commit C\"".to_string()));
+        assert!(mismatches.contains(&"commit b: tree differs\n  ~ README (contents differ)\n    expected: \"Hello B\"\n    found:    \"Hello\"\n  + c.txt (unexpected)".to_string()));
+        assert_eq!(mismatches.len(), 5, "{:#?}", mismatches);
+    }
+
+    // Specs for non-bare repositories can leave changes and operations in progress
+    #[test]
+    fn verify_in_progress_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let commits = r#"
+            { "type": "commit", "id": "a", "tree": { "README": "Hello\n" } },
+            { "type": "commit", "id": "b", "parents": ["a"], "tree": { "README": "Hello B\n" }, "branches": ["master"] },
+            { "type": "commit", "id": "c", "parents": ["a"], "tree": { "README": "Hello C\n" }, "branches": ["feature"] },
+            { "type": "worktree", "untracked": { "notes.txt": "Notes" } },
+        "#;
+        let operations = [
+            r#"{ "type": "merge", "id": "d", "commits": ["b", "c"], "in_progress": true }"#,
+            r#"{ "type": "state", "state": "rebase-merge", "onto": "b", "orig_head": "c", "branch": "feature", "todo": ["c"] }"#,
+        ];
+
+        for (i, operation) in operations.iter().enumerate() {
+            let spec = || -> Vec<Command> { serde_json::from_str(&format!("[{}{}]", commits, operation)).unwrap() };
+            let builder = spec().into_iter().fold(RepoBuilder::new(dir.path().join(i.to_string())), |builder, command| builder.command(command));
+            let generated = builder.build().unwrap();
+
+            let mismatches = verify_repository(generated.repository(), &spec()).unwrap();
+            assert!(mismatches.is_empty(), "{}: {:?}", operation, mismatches.iter().map(|mismatch| mismatch.to_string()).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn verify_graph_shape_test() {
+        let spec = "a: \"Initial\"\nd < a: \"Merge\"\nbranch master -> d\ntag 1.0.0 -> a\n";
+        assert_eq!(verify(REPO, spec), vec!["commit d: expected 1 parent(s), found 2"]);
+    }
+}
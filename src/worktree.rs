@@ -0,0 +1,301 @@
+// Uncommitted changes in a non-bare repository: staged and unstaged changes, and untracked and ignored files.
+// They're applied on top of the checked out HEAD.
+
+use git2::Repository;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use super::command::TreeNode;
+use super::error::Error;
+use super::interpreter::{blob_contents, check_path, MODE_EXECUTABLE, MODE_SYMLINK};
+
+// Sorted, so that the changes are applied in the same order on every run
+fn sorted(tree: &Option<HashMap<String, TreeNode>>) -> BTreeMap<&String, &TreeNode> {
+    tree.iter().flatten().collect()
+}
+
+fn io_error(path: &Path) -> impl Fn(io::Error) -> Error + '_ {
+    move |e| Error::Io(path.display().to_string(), e)
+}
+
+#[cfg(unix)]
+fn write_symlink(path: &Path, target: &[u8]) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    std::os::unix::fs::symlink(std::ffi::OsStr::from_bytes(target), path)
+}
+
+// Like Git with core.symlinks=false, the link is a file that contains its target
+#[cfg(not(unix))]
+fn write_symlink(path: &Path, target: &[u8]) -> io::Result<()> {
+    fs::write(path, target)
+}
+
+#[cfg(unix)]
+fn set_executable(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755))
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+// Removes a file, a symlink or a directory. Nothing happens if the path doesn't exist.
+fn remove(path: &Path) -> Result<(), Error> {
+    let result = match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(_) => Ok(())
+    };
+
+    result.map_err(io_error(path))
+}
+
+fn write_file(path: &Path, contents: &[u8], mode: i32) -> Result<(), Error> {
+    remove(path)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(io_error(parent))?;
+    }
+
+    let result = if mode == MODE_SYMLINK {
+        write_symlink(path, contents)
+    } else if mode == MODE_EXECUTABLE {
+        fs::write(path, contents).and_then(|_| set_executable(path))
+    } else {
+        fs::write(path, contents)
+    };
+
+    result.map_err(io_error(path))
+}
+
+// Untracked and ignored files can't be deleted, or be in the index
+fn write_untracked(repo: &Repository, workdir: &Path, tree: &Option<HashMap<String, TreeNode>>) -> Result<(), Error> {
+    let index = repo.index()?;
+
+    for (path, node) in sorted(tree) {
+        if index.get_path(Path::new(path), 0).is_some() {
+            return Err(Error::InvalidValue(format!("Untracked file is in the index: {}", path)));
+        }
+
+        match blob_contents(path, node)? {
+            Some((contents, mode)) => write_file(&workdir.join(path), &contents, mode)?,
+            None => return Err(Error::InvalidValue(format!("Untracked files can't be deleted: {}", path)))
+        }
+    }
+
+    Ok(())
+}
+
+pub fn apply_worktree(repo: &Repository,
+                      staged: &Option<HashMap<String, TreeNode>>,
+                      unstaged: &Option<HashMap<String, TreeNode>>,
+                      untracked: &Option<HashMap<String, TreeNode>>,
+                      ignored: &Option<HashMap<String, TreeNode>>) -> Result<(), Error> {
+    let workdir = match repo.workdir() {
+        Some(workdir) => workdir.to_path_buf(),
+        None => return Err(Error::InvalidValue("A worktree command needs a non-bare repository".to_string()))
+    };
+
+    // Nothing is written until every path is known to be inside the work tree
+    for tree in &[staged, unstaged, untracked, ignored] {
+        for path in sorted(tree).keys() {
+            check_path(path)?;
+        }
+    }
+
+    let mut index = repo.index()?;
+    for (path, node) in sorted(staged) {
+        match blob_contents(path, node)? {
+            Some((contents, mode)) => {
+                write_file(&workdir.join(path), &contents, mode)?;
+                index.add_path(Path::new(path))?;
+            },
+            None => {
+                remove(&workdir.join(path))?;
+                // A directory is deleted with everything in it
+                index.remove_path(Path::new(path))?;
+                index.remove_dir(Path::new(path), 0)?;
+            }
+        }
+    }
+    index.write()?;
+
+    for (path, node) in sorted(unstaged) {
+        match blob_contents(path, node)? {
+            Some((contents, mode)) => write_file(&workdir.join(path), &contents, mode)?,
+            None => remove(&workdir.join(path))?
+        }
+    }
+
+    write_untracked(repo, &workdir, untracked)?;
+    write_untracked(repo, &workdir, ignored)?;
+
+    if ignored.is_some() {
+        let exclude_path = repo.path().join("info").join("exclude");
+        fs::create_dir_all(repo.path().join("info")).map_err(io_error(repo.path()))?;
+
+        let mut exclude = fs::OpenOptions::new().create(true).append(true).open(&exclude_path).map_err(io_error(&exclude_path))?;
+        for path in sorted(ignored).keys() {
+            writeln!(exclude, "/{}", path).map_err(io_error(&exclude_path))?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::builder::RepoBuilder;
+    use super::super::command::Command;
+    use git2::Status;
+
+    #[test]
+    fn worktree_test() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let worktree: Command = serde_json::from_str(r##"{
+            "type": "worktree",
+            "staged":    { "new.txt": "New", "README": "Staged", "old.txt": null },
+            "unstaged":  { "README": "Unstaged", "deleted.txt": null, "run.sh": { "utf8": "#!/bin/sh", "executable": true } },
+            "untracked": { "notes/todo.txt": "Todo" },
+            "ignored":   { "build/output.o": "Binary" }
+        }"##).unwrap();
+
+        let generated = RepoBuilder::new(dir.path())
+            .commit("a").file("README", "Hello").file("old.txt", "Old").file("deleted.txt", "Deleted").file("run.sh", "").branch("master")
+            .finish()
+            .command(worktree)
+            .build()
+            .unwrap();
+
+        let repo = generated.repository();
+        let status = |path: &str| repo.status_file(std::path::Path::new(path)).unwrap();
+
+        assert_eq!(status("new.txt"), Status::INDEX_NEW);
+        assert_eq!(status("README"), Status::INDEX_MODIFIED | Status::WT_MODIFIED);
+        assert_eq!(status("old.txt"), Status::INDEX_DELETED);
+        assert_eq!(status("deleted.txt"), Status::WT_DELETED);
+        assert_eq!(status("notes/todo.txt"), Status::WT_NEW);
+        assert_eq!(status("build/output.o"), Status::IGNORED);
+
+        let workdir = repo.workdir().unwrap();
+        assert_eq!(std::fs::read_to_string(workdir.join("README")).unwrap(), "Unstaged");
+        assert!(!workdir.join("old.txt").exists());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(status("run.sh"), Status::WT_MODIFIED);
+            assert_eq!(std::fs::metadata(workdir.join("run.sh")).unwrap().permissions().mode() & 0o111, 0o111);
+        }
+    }
+
+    #[test]
+    fn worktree_errors_test() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let tracked: Command = serde_json::from_str(r#"{ "type": "worktree", "untracked": { "README": "Tracked" } }"#).unwrap();
+        let result = RepoBuilder::new(dir.path().join("tracked"))
+            .commit("a").file("README", "Hello").branch("master")
+            .finish()
+            .command(tracked)
+            .build();
+        assert!(result.is_err());
+
+        let bare: Command = serde_json::from_str(r#"{ "type": "worktree", "untracked": { "new.txt": "New" } }"#).unwrap();
+        let result = RepoBuilder::new(dir.path().join("bare")).bare(true)
+            .commit("a").branch("master")
+            .finish()
+            .command(bare)
+            .build();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn worktree_path_test() {
+        let dir = tempfile::tempdir().unwrap();
+
+        for (index, path) in ["../escaped.txt", "notes/../../escaped.txt", "/tmp/absolute.txt", ".git/config", "notes/.GIT/x", "notes//x", "./x", "notes/"].iter().enumerate() {
+            for field in &["staged", "unstaged", "untracked", "ignored"] {
+                let command: Command = serde_json::from_value(serde_json::json!({ "type": "worktree", *field: { *path: "Escaped" } })).unwrap();
+
+                let repo_dir = dir.path().join(format!("{}-{}", index, field));
+                let result = RepoBuilder::new(&repo_dir)
+                    .commit("a").file("README", "Hello").branch("master")
+                    .finish()
+                    .command(command)
+                    .build();
+
+                assert_eq!(result.err().map(|e| e.to_string()), Some(format!("command at index 1: Invalid path: {}", path)));
+            }
+        }
+
+        assert!(!dir.path().join("escaped.txt").exists());
+        assert!(!std::path::Path::new("/tmp/absolute.txt").exists());
+    }
+
+    #[test]
+    fn head_after_worktree_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let worktree = || serde_json::from_str::<Command>(r#"{ "type": "worktree", "unstaged": { "README": "Changed" } }"#).unwrap();
+        let repo_builder = |name: &str| RepoBuilder::new(dir.path().join(name))
+            .commit("a").file("README", "Hello").branch("master")
+            .commit("b").parents(["a"]).file("README", "Hello B")
+            .finish()
+            .command(worktree());
+
+        assert!(repo_builder("head").head("feature").build().is_err());
+        assert!(repo_builder("detach").detach_head("b").build().is_err());
+        assert!(repo_builder("move").branch("master", "b").build().is_err());
+
+        // Other branches can still be created
+        let generated = repo_builder("other").branch("feature", "b").build().unwrap();
+        assert_eq!(generated.repository().refname_to_id("refs/heads/feature").unwrap(), generated.oid("b").unwrap());
+    }
+
+    // Operations that check out another commit or rewrite the index don't discard the worktree's changes
+    #[test]
+    fn state_after_worktree_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let build = |name: &str, worktree: &str, state: &str| RepoBuilder::new(dir.path().join(name))
+            .commit("a").file("README", "one").file("other", "other")
+            .commit("b").parents(["a"]).file("README", "two").file("other", "other")
+            .commit("c").parents(["a"]).file("README", "one").file("other", "changed").branch("master")
+            .finish()
+            .command(serde_json::from_str(worktree).unwrap())
+            .command(serde_json::from_str(state).unwrap())
+            .build();
+
+        let unstaged = r#"{ "type": "worktree", "unstaged": { "README": "DIRTY" } }"#;
+        let staged = r#"{ "type": "worktree", "staged": { "other": "DIRTY" } }"#;
+        let states = [
+            r#"{ "type": "state", "state": "rebase-merge", "onto": "b", "orig_head": "c", "todo": ["c"] }"#,
+            r#"{ "type": "state", "state": "rebase-apply", "onto": "b", "orig_head": "c", "todo": ["c"] }"#,
+            r#"{ "type": "state", "state": "cherry-pick", "commit": "b" }"#,
+            r#"{ "type": "state", "state": "revert", "commit": "c" }"#,
+            r#"{ "type": "state", "state": "bisect", "bad": "c", "good": ["a"], "head": "b" }"#,
+        ];
+        for (i, state) in states.iter().enumerate() {
+            for (j, worktree) in [unstaged, staged].iter().enumerate() {
+                let name = format!("{}-{}", i, j);
+                let error = build(&name, worktree, state).err().unwrap().to_string();
+                assert!(error.contains("because of uncommitted changes"), "{} after {}: {}", state, worktree, error);
+            }
+        }
+
+        // Untracked files are kept
+        let generated = build("untracked", r#"{ "type": "worktree", "untracked": { "new.txt": "New" } }"#, states[0]).unwrap();
+        let workdir = generated.repository().workdir().unwrap();
+        assert_eq!(std::fs::read_to_string(workdir.join("new.txt")).unwrap(), "New");
+        assert_eq!(std::fs::read_to_string(workdir.join("README")).unwrap(), "two");
+
+        // A bisect that doesn't check out a commit doesn't touch the changes
+        let generated = build("bisect", unstaged, r#"{ "type": "state", "state": "bisect", "bad": "c", "good": ["a"] }"#).unwrap();
+        assert_eq!(std::fs::read_to_string(generated.repository().workdir().unwrap().join("README")).unwrap(), "DIRTY");
+    }
+}